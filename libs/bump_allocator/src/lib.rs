@@ -7,108 +7,271 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-#![feature(const_fn)]
-#![feature(allocator)]
-
-#![allocator]
 #![no_std]
 
-use spin::Mutex;
-
-extern crate spin;
-
-pub const HEAP_START: usize = 0o_000_001_000_000_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
-
-static BUMP_ALLOCATOR: Mutex<BumpAllocator> = Mutex::new(
-    BumpAllocator::new(HEAP_START, HEAP_SIZE));
+use core::cell::Cell;
+use core::{mem, ptr, slice};
 
+/// An arena that hands out memory from a fixed region by bumping a pointer.
+///
+/// Single allocations can't be freed. Instead, the whole arena can be `reset` or rolled back to
+/// a `Mark`. Both require a mutable reference, so no allocations can be in use afterwards.
+///
+/// The destructors of values that are allocated in the arena are never run.
 #[derive(Debug)]
-struct BumpAllocator {
-    heap_start: usize,
-    heap_size: usize,
-    next: usize,
+pub struct BumpArena {
+    start: usize,
+    end: usize,
+    next: Cell<usize>,
 }
 
-impl BumpAllocator {
-    /// Create a new allocator, which uses the memory in the
-    /// range [heap_start, heap_start + heap_size).
-    const fn new(heap_start: usize, heap_size: usize) -> BumpAllocator {
-        BumpAllocator {
-            heap_start: heap_start,
-            heap_size: heap_size,
-            next: heap_start,
+/// A position in a `BumpArena` that the arena can be rolled back to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mark(usize);
+
+impl BumpArena {
+    /// Creates a new arena, which uses the memory in the
+    /// range [start, start + size).
+    ///
+    /// # Safety
+    ///
+    /// The region must be valid memory that is not used for anything else while the arena
+    /// exists.
+    pub unsafe fn new(start: usize, size: usize) -> BumpArena {
+        let end = start.checked_add(size);
+        assert!(end.is_some(), "arena region overflows the address space");
+        BumpArena {
+            start: start,
+            end: end.unwrap(),
+            next: Cell::new(start),
         }
     }
 
     /// Allocates a block of memory with the given size and alignment.
-    fn allocate(&mut self, size: usize, align: usize) -> Option<*mut u8> {
-        let alloc_start = align_up(self.next, align);
+    pub fn allocate(&self, size: usize, align: usize) -> Option<*mut u8> {
+        let alloc_start = align_up(self.next.get(), align);
         let alloc_end = alloc_start.saturating_add(size);
 
-        if alloc_end <= self.heap_start + self.heap_size {
-            self.next = alloc_end;
+        if alloc_end <= self.end {
+            self.next.set(alloc_end);
             Some(alloc_start as *mut u8)
         } else {
             None
         }
     }
+
+    /// Moves `value` into the arena and returns a reference to it.
+    // each call returns a new block of the arena, so the mutable references never alias
+    pub fn alloc<T>(&self, value: T) -> Option<&mut T> {
+        self.allocate(mem::size_of::<T>(), mem::align_of::<T>()).map(|ptr| {
+            let ptr = ptr as *mut T;
+            unsafe {
+                ptr::write(ptr, value);
+                &mut *ptr
+            }
+        })
+    }
+
+    /// Allocates a slice of `len` elements that are initialized with `value`.
+    // each call returns a new block of the arena, so the mutable references never alias
+    pub fn alloc_slice<T: Copy>(&self, len: usize, value: T) -> Option<&mut [T]> {
+        let size = match mem::size_of::<T>().checked_mul(len) {
+            Some(size) => size,
+            None => return None,
+        };
+        self.allocate(size, mem::align_of::<T>()).map(|ptr| {
+            let ptr = ptr as *mut T;
+            unsafe {
+                let mut element = ptr;
+                for _ in 0..len {
+                    ptr::write(element, value);
+                    element = element.offset(1);
+                }
+                slice::from_raw_parts_mut(ptr, len)
+            }
+        })
+    }
+
+    /// Returns the current position, which can be passed to `rollback` later.
+    pub fn mark(&self) -> Mark {
+        Mark(self.next.get())
+    }
+
+    /// Frees all allocations that were made after `mark` was created.
+    pub fn rollback(&mut self, mark: Mark) {
+        assert!(mark.0 >= self.start && mark.0 <= self.next.get(),
+                "mark does not belong to this arena or was already rolled back");
+        self.next.set(mark.0);
+    }
+
+    /// Frees all allocations.
+    pub fn reset(&mut self) {
+        self.next.set(self.start);
+    }
+
+    /// Returns the number of bytes that are allocated, including alignment padding.
+    pub fn used(&self) -> usize {
+        self.next.get() - self.start
+    }
+
+    /// Returns the number of bytes that are still available.
+    pub fn remaining(&self) -> usize {
+        self.end - self.next.get()
+    }
 }
 
 /// Align downwards. Returns the greatest x with alignment `align`
 /// so that x <= addr. The alignment must be a power of 2.
 pub fn align_down(addr: usize, align: usize) -> usize {
-    if align.is_power_of_two() {
-        addr & !(align - 1)
-    } else if align == 0 {
-        addr
-    } else {
-        panic!("`align` must be a power of 2");
-    }
+    assert!(align.is_power_of_two(), "`align` must be a power of 2");
+    addr & !(align - 1)
 }
 
 /// Align upwards. Returns the smallest x with alignment `align`
 /// so that x >= addr. The alignment must be a power of 2.
 pub fn align_up(addr: usize, align: usize) -> usize {
-    align_down(addr + align - 1, align)
+    align_down(addr + (align - 1), align)
 }
 
-#[no_mangle]
-pub extern fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
-    BUMP_ALLOCATOR.lock().allocate(size, align).expect("out of memory")
-}
+#[cfg(test)]
+mod tests {
+    use super::{align_down, align_up, BumpArena};
+    use core::mem;
 
-#[no_mangle]
-pub extern fn __rust_deallocate(_ptr: *mut u8, _size: usize,
-    _align: usize)
-{
-    // just leak it
-}
+    /// Creates an arena for the given buffer, which must outlive it.
+    fn arena(buffer: &mut [u64]) -> BumpArena {
+        unsafe { BumpArena::new(buffer.as_mut_ptr() as usize, mem::size_of_val(buffer)) }
+    }
 
-#[no_mangle]
-pub extern fn __rust_usable_size(size: usize, _align: usize) -> usize {
-    size
-}
+    #[test]
+    fn allocate() {
+        let mut buffer = [0u64; 8];
+        let arena = arena(&mut buffer);
+        let start = buffer.as_ptr() as usize;
 
-#[no_mangle]
-pub extern fn __rust_reallocate_inplace(_ptr: *mut u8, size: usize,
-    _new_size: usize, _align: usize) -> usize
-{
-    size
-}
+        assert_eq!(arena.allocate(1, 1), Some(start as *mut u8));
+        // the padding for the alignment is skipped
+        assert_eq!(arena.allocate(8, 8), Some((start + 8) as *mut u8));
+        assert_eq!(arena.used(), 16);
+        assert_eq!(arena.remaining(), 48);
 
-#[no_mangle]
-pub extern fn __rust_reallocate(ptr: *mut u8, size: usize, new_size: usize,
-                                align: usize) -> *mut u8 {
-    use core::{ptr, cmp};
+        assert_eq!(arena.allocate(49, 1), None);
+        assert_eq!(arena.allocate(48, 1), Some((start + 16) as *mut u8));
+        assert_eq!(arena.allocate(1, 1), None);
+        assert_eq!(arena.allocate(!0, 1), None);
+    }
 
-    // from: https://github.com/rust-lang/rust/blob/
-    //     c66d2380a810c9a2b3dbb4f93a830b101ee49cc2/
-    //     src/liballoc_system/lib.rs#L98-L101
+    #[test]
+    #[should_panic]
+    fn region_overflow() {
+        unsafe { BumpArena::new(!0 - 8, 16) };
+    }
 
-    let new_ptr = __rust_allocate(new_size, align);
-    unsafe { ptr::copy(ptr, new_ptr, cmp::min(size, new_size)) };
-    __rust_deallocate(ptr, size, align);
-    new_ptr
-}
+    #[test]
+    fn alloc() {
+        let mut buffer = [0u64; 8];
+        let arena = arena(&mut buffer);
+
+        let a = arena.alloc(1u8).unwrap();
+        let b = arena.alloc(0x1234_5678u32).unwrap();
+        assert_eq!(*a, 1);
+        assert_eq!(*b, 0x1234_5678);
+        assert_eq!(b as *mut u32 as usize % mem::align_of::<u32>(), 0);
+        *a = 2;
+        assert_eq!(*b, 0x1234_5678);
+
+        assert!(arena.alloc([0u64; 8]).is_none());
+    }
+
+    #[test]
+    fn alloc_slice() {
+        let mut buffer = [0u64; 8];
+        let arena = arena(&mut buffer);
+
+        let slice = arena.alloc_slice(5, 0xabu16).unwrap();
+        assert_eq!(slice, &[0xab; 5]);
+        slice[4] = 0;
+        let empty = arena.alloc_slice::<u64>(0, 0).unwrap();
+        assert!(empty.is_empty());
+
+        assert!(arena.alloc_slice(8, 0u64).is_none());
+        assert!(arena.alloc_slice(!0, 0u64).is_none());
+        assert_eq!(arena.alloc_slice(6, 1u64).unwrap(), &[1; 6]);
+    }
+
+    #[test]
+    fn reset() {
+        let mut buffer = [0u64; 8];
+        let mut arena = arena(&mut buffer);
+
+        let first = arena.allocate(64, 8).unwrap();
+        assert_eq!(arena.remaining(), 0);
+        arena.reset();
+        assert_eq!(arena.used(), 0);
+        assert_eq!(arena.allocate(64, 8), Some(first));
+    }
+
+    #[test]
+    fn mark_and_rollback() {
+        let mut buffer = [0u64; 8];
+        let mut arena = arena(&mut buffer);
+
+        arena.allocate(16, 8).unwrap();
+        let mark = arena.mark();
+        let second = arena.allocate(16, 8).unwrap();
+        arena.allocate(16, 8).unwrap();
+        assert_eq!(arena.used(), 48);
 
+        arena.rollback(mark);
+        assert_eq!(arena.used(), 16);
+        assert_eq!(arena.allocate(16, 8), Some(second));
+    }
+
+    #[test]
+    #[should_panic]
+    fn rollback_twice() {
+        let mut buffer = [0u64; 8];
+        let mut arena = arena(&mut buffer);
+
+        let outer_mark = arena.mark();
+        arena.allocate(16, 8).unwrap();
+        let inner_mark = arena.mark();
+        arena.rollback(outer_mark);
+        // the inner mark is beyond the current position now
+        arena.rollback(inner_mark);
+    }
+
+    #[test]
+    fn align() {
+        assert_eq!(align_down(0x1234, 0x1000), 0x1000);
+        assert_eq!(align_up(0x1234, 0x1000), 0x2000);
+        assert_eq!(align_down(0x2000, 0x1000), 0x2000);
+        assert_eq!(align_up(0x2000, 0x1000), 0x2000);
+        assert_eq!(align_up(0, 8), 0);
+        assert_eq!(align_up(7, 1), 7);
+    }
+
+    #[test]
+    fn align_edge_cases() {
+        assert_eq!(align_down(0, 0x1000), 0);
+        assert_eq!(align_down(0xfff, 0x1000), 0);
+        assert_eq!(align_down(!0, 1), !0);
+        assert_eq!(align_down(!0, 0x1000), !0xfff);
+        // already aligned addresses at the end of the address space don't overflow
+        assert_eq!(align_up(!0xfff, 0x1000), !0xfff);
+        assert_eq!(align_up(1, 1 << 63), 1 << 63);
+        assert_eq!(align_down(0xffff_8000_1234_5678, 1 << 47), 0xffff_8000_0000_0000);
+    }
+
+    #[test]
+    #[should_panic]
+    fn align_not_power_of_two() {
+        align_down(0x1234, 3);
+    }
+
+    #[test]
+    #[should_panic]
+    fn align_zero() {
+        align_up(0x1234, 0);
+    }
+}
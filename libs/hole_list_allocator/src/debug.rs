@@ -0,0 +1,234 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use core::{cmp, fmt, mem, ptr};
+#[cfg(not(test))]
+use super::{allocate_block, deallocate_block};
+#[cfg(test)]
+use self::tests::{allocate_block, deallocate_block};
+
+/// The pattern of the red zones around each block.
+const RED_ZONE_BYTE: u8 = 0xfd;
+/// The size of the red zone after each block.
+const BACK_RED_ZONE_SIZE: usize = 16;
+/// The pattern that freed blocks are filled with.
+const POISON_BYTE: u8 = 0x6b;
+
+const CANARY: usize = 0xfdfd_fdfd_fdfd_fdfd;
+const ALLOCATED: usize = 0xa110_ca7e_a110_ca7e;
+const FREED: usize = 0xf4ee_d000_f4ee_d000;
+
+/// Stored directly before each block. The front red zone consists of the `canary` field and
+/// the padding before the header.
+///
+/// When the block is freed, the inner allocators store their free list data at the start of the
+/// header, so `state` must not be one of the first two fields.
+#[repr(C)]
+struct Header {
+    size: usize,
+    align: usize,
+    state: usize,
+    canary: usize,
+}
+
+/// Returns the distance between the start of the inner block and the user block.
+fn front_size(align: usize) -> usize {
+    let header_size = mem::size_of::<Header>();
+    (header_size + align - 1) / align * align
+}
+
+/// Returns the size and alignment of the inner block, or `None` if the size overflows.
+fn inner_layout(size: usize, align: usize) -> Option<(usize, usize)> {
+    front_size(align)
+        .checked_add(size)
+        .and_then(|inner_size| inner_size.checked_add(BACK_RED_ZONE_SIZE))
+        .map(|inner_size| (inner_size, cmp::max(align, mem::align_of::<Header>())))
+}
+
+unsafe fn header(ptr: *mut u8) -> *mut Header {
+    (ptr as *mut Header).offset(-1)
+}
+
+pub fn allocate(size: usize, align: usize) -> Option<*mut u8> {
+    let (inner_size, inner_align) = match inner_layout(size, align) {
+        Some(layout) => layout,
+        None => return None,
+    };
+    let inner = match allocate_block(inner_size, inner_align) {
+        Some(inner) => inner,
+        None => return None,
+    };
+
+    unsafe {
+        let ptr = inner.offset(front_size(align) as isize);
+        let padding = front_size(align) - mem::size_of::<Header>();
+        ptr::write_bytes(inner, RED_ZONE_BYTE, padding);
+        ptr::write(header(ptr),
+                   Header {
+                       size: size,
+                       align: align,
+                       state: ALLOCATED,
+                       canary: CANARY,
+                   });
+        ptr::write_bytes(ptr.offset(size as isize), RED_ZONE_BYTE, BACK_RED_ZONE_SIZE);
+        Some(ptr)
+    }
+}
+
+pub unsafe fn deallocate(ptr: *mut u8, size: usize, align: usize) {
+    let header = &mut *header(ptr);
+    match header.state {
+        ALLOCATED => {}
+        FREED => report(ptr, size, align, "double free"),
+        _ => report(ptr, size, align, "block is not allocated or its header was overwritten"),
+    }
+    if header.size != size || header.align != align {
+        report(ptr,
+               size,
+               align,
+               format_args!("block was allocated with size {} and align {}",
+                            header.size,
+                            header.align));
+    }
+
+    let inner = ptr.offset(-(front_size(align) as isize));
+    let padding = front_size(align) - mem::size_of::<Header>();
+    if header.canary != CANARY || !is_filled(inner, padding, RED_ZONE_BYTE) {
+        report(ptr, size, align, "front red zone was overwritten (buffer underflow)");
+    }
+    if !is_filled(ptr.offset(size as isize), BACK_RED_ZONE_SIZE, RED_ZONE_BYTE) {
+        report(ptr, size, align, "back red zone was overwritten (buffer overflow)");
+    }
+
+    ptr::write_bytes(ptr, POISON_BYTE, size);
+    header.state = FREED;
+
+    let (inner_size, inner_align) = inner_layout(size, align)
+        .expect("the block was allocated with this layout");
+    deallocate_block(inner, inner_size, inner_align);
+}
+
+/// The red zones must stay at the end of the block, so the usable size is the requested size.
+pub fn usable_size(size: usize, _align: usize) -> usize {
+    size
+}
+
+unsafe fn is_filled(start: *const u8, len: usize, byte: u8) -> bool {
+    (0..len).all(|offset| *start.offset(offset as isize) == byte)
+}
+
+fn report<D>(ptr: *mut u8, size: usize, align: usize, problem: D) -> !
+    where D: fmt::Display
+{
+    panic!("heap corruption at {:#x} (size {}, align {}): {}",
+           ptr as usize,
+           size,
+           align,
+           problem);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use align_up;
+    use std::vec::Vec;
+
+    /// The kernel heap can't be used on the host, so the inner blocks are leaked from the system
+    /// allocator. This keeps freed blocks readable, so that double frees can be detected.
+    pub fn allocate_block(size: usize, align: usize) -> Option<*mut u8> {
+        let buffer: Vec<u8> = vec![0; size + align];
+        let block = align_up(buffer.as_ptr() as usize, align);
+        mem::forget(buffer);
+        Some(block as *mut u8)
+    }
+
+    pub unsafe fn deallocate_block(_ptr: *mut u8, _size: usize, _align: usize) {}
+
+    #[test]
+    fn allocate_and_deallocate() {
+        for &(size, align) in &[(1, 1), (24, 8), (100, 64), (4096, 4096)] {
+            let ptr = allocate(size, align).unwrap();
+            assert_eq!(ptr as usize % align, 0);
+            unsafe {
+                ptr::write_bytes(ptr, 0, size);
+                deallocate(ptr, size, align);
+            }
+        }
+    }
+
+    #[test]
+    fn huge_allocation_fails() {
+        assert_eq!(allocate(usize::max_value() - 8, 8), None);
+    }
+
+    #[test]
+    fn freed_blocks_are_poisoned() {
+        let ptr = allocate(32, 8).unwrap();
+        unsafe {
+            ptr::write_bytes(ptr, 0, 32);
+            deallocate(ptr, 32, 8);
+            assert!(is_filled(ptr, 32, POISON_BYTE));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "back red zone was overwritten")]
+    fn buffer_overflow() {
+        let ptr = allocate(32, 8).unwrap();
+        unsafe {
+            *ptr.offset(32) = 0;
+            deallocate(ptr, 32, 8);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "front red zone was overwritten")]
+    fn buffer_underflow() {
+        let ptr = allocate(32, 8).unwrap();
+        unsafe {
+            *ptr.offset(-1) = 0;
+            deallocate(ptr, 32, 8);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "front red zone was overwritten")]
+    fn buffer_underflow_into_padding() {
+        // the header is preceded by padding, which is part of the front red zone
+        let ptr = allocate(32, 64).unwrap();
+        unsafe {
+            *ptr.offset(-(mem::size_of::<Header>() as isize) - 1) = 0;
+            deallocate(ptr, 32, 64);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn double_free() {
+        let ptr = allocate(32, 8).unwrap();
+        unsafe {
+            deallocate(ptr, 32, 8);
+            deallocate(ptr, 32, 8);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "block was allocated with size 32 and align 8")]
+    fn size_mismatch() {
+        let ptr = allocate(32, 8).unwrap();
+        unsafe { deallocate(ptr, 64, 8) };
+    }
+
+    #[test]
+    #[should_panic(expected = "block is not allocated")]
+    fn invalid_free() {
+        let mut buffer = vec![0u64; 16];
+        unsafe { deallocate(buffer.as_mut_ptr().offset(8) as *mut u8, 32, 8) };
+    }
+}
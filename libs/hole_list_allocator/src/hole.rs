@@ -0,0 +1,420 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use core::{mem, ptr};
+use align_up;
+
+/// A free memory region. The `Hole` struct is stored at the start of the region itself.
+struct Hole {
+    size: usize,
+    next: *mut Hole,
+}
+
+/// A first fit allocator that keeps the free regions in a linked list sorted by address.
+///
+/// Adjacent holes are merged on deallocation. Since the list is sorted, blocks can also be
+/// resized in place if the next hole directly follows them.
+///
+/// This replaces the `linked_list_allocator` crate, whose `Heap` only supports allocating and
+/// freeing. Its hole list is private, so it can neither grow a block into the following hole nor
+/// tell us the padded size of a block, which `__rust_reallocate_inplace` and
+/// `__rust_usable_size` need.
+pub struct HoleList {
+    // a dummy hole of size 0 that points to the first hole
+    first: Hole,
+}
+
+// the holes are owned by the list
+unsafe impl Send for HoleList {}
+
+impl HoleList {
+    /// Creates a hole list that manages the memory region `start..(start + size)`.
+    ///
+    /// This is unsafe because the region must be valid and unused. `start` must be aligned to
+    /// the alignment of `usize`.
+    pub unsafe fn new(start: usize, size: usize) -> HoleList {
+        assert!(start % mem::align_of::<Hole>() == 0);
+        let mut list = HoleList {
+            first: Hole {
+                size: 0,
+                next: ptr::null_mut(),
+            },
+        };
+        list.deallocate(start as *mut u8, size);
+        list
+    }
+
+    /// The smallest free region that can be stored in the list.
+    pub fn min_size() -> usize {
+        mem::size_of::<Hole>()
+    }
+
+    /// Returns the size of the memory block that is used for an allocation of `size` bytes.
+    ///
+    /// Blocks must be large enough to become a hole when they are freed, and their size must be a
+    /// multiple of the hole alignment, so that the next block can become a hole, too.
+    pub fn block_size(size: usize) -> usize {
+        let size = if size < HoleList::min_size() {
+            HoleList::min_size()
+        } else {
+            size
+        };
+        align_up(size, mem::align_of::<Hole>())
+    }
+
+    /// Allocates a block of `size` bytes from the first hole that is large enough.
+    pub fn allocate_first_fit(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        let size = HoleList::block_size(size);
+        unsafe {
+            let mut previous: *mut Hole = &mut self.first;
+            while !(*previous).next.is_null() {
+                let hole = (*previous).next;
+                if let Some((start, front_padding, back_padding)) = split_hole(hole, size, align) {
+                    let after = if back_padding > 0 {
+                        let back_hole = (start + size) as *mut Hole;
+                        ptr::write(back_hole,
+                                   Hole {
+                                       size: back_padding,
+                                       next: (*hole).next,
+                                   });
+                        back_hole
+                    } else {
+                        (*hole).next
+                    };
+                    if front_padding > 0 {
+                        // the front of the hole stays free
+                        (*hole).size = front_padding;
+                        (*hole).next = after;
+                    } else {
+                        (*previous).next = after;
+                    }
+                    return Some(start as *mut u8);
+                }
+                previous = hole;
+            }
+        }
+        None
+    }
+
+    /// Frees the block at `ptr` and merges it with the adjacent holes.
+    ///
+    /// This is unsafe because the block must have been allocated from this list with the given
+    /// size, or it must be a new valid memory region.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, size: usize) {
+        let address = ptr as usize;
+        let size = HoleList::block_size(size);
+
+        let first: *mut Hole = &mut self.first;
+        let previous = self.previous_hole(address);
+        let next = (*previous).next;
+        assert!(previous == first || previous as usize + (*previous).size <= address,
+                "block {:#x} overlaps a free region",
+                address);
+        assert!(next.is_null() || address + size <= next as usize,
+                "block {:#x} overlaps a free region",
+                address);
+
+        let merge_previous = previous != first && previous as usize + (*previous).size == address;
+        let merge_next = !next.is_null() && address + size == next as usize;
+        match (merge_previous, merge_next) {
+            (true, true) => {
+                (*previous).size += size + (*next).size;
+                (*previous).next = (*next).next;
+            }
+            (true, false) => (*previous).size += size,
+            (false, true) => {
+                let hole = address as *mut Hole;
+                ptr::write(hole,
+                           Hole {
+                               size: size + (*next).size,
+                               next: (*next).next,
+                           });
+                (*previous).next = hole;
+            }
+            (false, false) => {
+                let hole = address as *mut Hole;
+                ptr::write(hole,
+                           Hole {
+                               size: size,
+                               next: next,
+                           });
+                (*previous).next = hole;
+            }
+        }
+    }
+
+    /// Tries to resize the block at `ptr` from `size` to `new_size` bytes without moving it.
+    /// Returns whether the block was resized.
+    ///
+    /// A block can grow if it's directly followed by a large enough hole. It can shrink if the
+    /// freed tail can become a hole or be merged with a directly following hole.
+    ///
+    /// This is unsafe because the block must have been allocated from this list with `size`.
+    pub unsafe fn resize_in_place(&mut self, ptr: *mut u8, size: usize, new_size: usize) -> bool {
+        let address = ptr as usize;
+        let size = HoleList::block_size(size);
+        let new_size = HoleList::block_size(new_size);
+        if new_size == size {
+            return true;
+        }
+
+        let block_end = address + size;
+        let previous = self.previous_hole(address);
+        let next = (*previous).next;
+        let next_is_adjacent = !next.is_null() && next as usize == block_end;
+
+        if new_size < size {
+            let tail = size - new_size;
+            if tail >= HoleList::min_size() {
+                self.deallocate((address + new_size) as *mut u8, tail);
+            } else if next_is_adjacent {
+                // move the start of the next hole back
+                let hole = (address + new_size) as *mut Hole;
+                ptr::write(hole,
+                           Hole {
+                               size: (*next).size + tail,
+                               next: (*next).next,
+                           });
+                (*previous).next = hole;
+            } else {
+                // the tail is too small to become a hole
+                return false;
+            }
+            return true;
+        }
+
+        let needed = new_size - size;
+        if !next_is_adjacent || (*next).size < needed {
+            return false;
+        }
+        let remaining = (*next).size - needed;
+        if remaining == 0 {
+            (*previous).next = (*next).next;
+        } else if remaining >= HoleList::min_size() {
+            // move the start of the next hole forward
+            let hole = (address + new_size) as *mut Hole;
+            let next_next = (*next).next;
+            ptr::write(hole,
+                       Hole {
+                           size: remaining,
+                           next: next_next,
+                       });
+            (*previous).next = hole;
+        } else {
+            // the rest of the hole would be too small to be stored
+            return false;
+        }
+        true
+    }
+
+    /// Returns the last hole that starts before `address`, or the dummy hole if there is none.
+    unsafe fn previous_hole(&mut self, address: usize) -> *mut Hole {
+        let mut previous: *mut Hole = &mut self.first;
+        while !(*previous).next.is_null() && ((*previous).next as usize) < address {
+            previous = (*previous).next;
+        }
+        previous
+    }
+}
+
+/// Tries to place a block of `size` bytes with the given alignment in `hole`. Returns the start
+/// address of the block and the sizes of the free regions before and after it.
+///
+/// The free regions must either be empty or large enough to become holes themselves.
+unsafe fn split_hole(hole: *mut Hole, size: usize, align: usize) -> Option<(usize, usize, usize)> {
+    let hole_start = hole as usize;
+    let hole_end = hole_start + (*hole).size;
+
+    let mut start = align_up(hole_start, align);
+    if start != hole_start && start - hole_start < HoleList::min_size() {
+        start = align_up(hole_start + HoleList::min_size(), align);
+    }
+    let end = start + size;
+    if end > hole_end {
+        return None;
+    }
+    let back_padding = hole_end - end;
+    if back_padding != 0 && back_padding < HoleList::min_size() {
+        return None;
+    }
+    Some((start, start - hole_start, back_padding))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    const SIZE: usize = 0x1000;
+
+    /// Creates a hole list for a buffer of `SIZE` bytes that starts at a 4096 byte boundary.
+    /// The buffer must be kept alive while the list is used.
+    fn new_list() -> (Vec<u64>, usize, HoleList) {
+        let buffer = vec![0; (SIZE + 0x1000) / 8];
+        let start = align_up(buffer.as_ptr() as usize, 0x1000);
+        let list = unsafe { HoleList::new(start, SIZE) };
+        (buffer, start, list)
+    }
+
+    /// Returns the offset and size of each hole.
+    fn holes(list: &HoleList, start: usize) -> Vec<(usize, usize)> {
+        let mut holes = Vec::new();
+        let mut hole = list.first.next;
+        while !hole.is_null() {
+            unsafe {
+                holes.push((hole as usize - start, (*hole).size));
+                hole = (*hole).next;
+            }
+        }
+        holes
+    }
+
+    fn allocate(list: &mut HoleList, start: usize, size: usize, align: usize) -> Option<usize> {
+        list.allocate_first_fit(size, align).map(|ptr| ptr as usize - start)
+    }
+
+    #[test]
+    fn allocate_first_fit() {
+        let (_buffer, start, mut list) = new_list();
+        assert_eq!(allocate(&mut list, start, 64, 8), Some(0));
+        assert_eq!(allocate(&mut list, start, 64, 8), Some(64));
+        assert_eq!(holes(&list, start), vec![(128, SIZE - 128)]);
+
+        assert_eq!(allocate(&mut list, start, SIZE, 8), None);
+        assert_eq!(allocate(&mut list, start, SIZE - 128, 8), Some(128));
+        assert_eq!(holes(&list, start), vec![]);
+        assert_eq!(allocate(&mut list, start, 8, 8), None);
+    }
+
+    #[test]
+    fn block_size() {
+        assert_eq!(HoleList::block_size(1), HoleList::min_size());
+        assert_eq!(HoleList::block_size(17), 24);
+
+        // small blocks use the minimal size, so that they can become holes when they are freed
+        let (_buffer, start, mut list) = new_list();
+        assert_eq!(allocate(&mut list, start, 1, 1), Some(0));
+        assert_eq!(allocate(&mut list, start, 1, 1), Some(HoleList::min_size()));
+    }
+
+    #[test]
+    fn deallocate_and_merge() {
+        let (_buffer, start, mut list) = new_list();
+        let a = list.allocate_first_fit(64, 8).unwrap();
+        let b = list.allocate_first_fit(64, 8).unwrap();
+        let c = list.allocate_first_fit(64, 8).unwrap();
+        let _d = list.allocate_first_fit(64, 8).unwrap();
+
+        unsafe { list.deallocate(a, 64) };
+        assert_eq!(holes(&list, start), vec![(0, 64), (256, SIZE - 256)]);
+
+        // merged with the previous hole
+        unsafe { list.deallocate(b, 64) };
+        assert_eq!(holes(&list, start), vec![(0, 128), (256, SIZE - 256)]);
+
+        // merged with both neighbors is not possible, since `d` is still allocated
+        unsafe { list.deallocate(c, 64) };
+        assert_eq!(holes(&list, start), vec![(0, 192), (256, SIZE - 256)]);
+    }
+
+    #[test]
+    fn deallocate_merges_both_neighbors() {
+        let (_buffer, start, mut list) = new_list();
+        let a = list.allocate_first_fit(64, 8).unwrap();
+        let b = list.allocate_first_fit(64, 8).unwrap();
+        let c = list.allocate_first_fit(64, 8).unwrap();
+
+        unsafe { list.deallocate(c, 64) };
+        assert_eq!(holes(&list, start), vec![(128, SIZE - 128)]);
+        unsafe { list.deallocate(a, 64) };
+        assert_eq!(holes(&list, start), vec![(0, 64), (128, SIZE - 128)]);
+        unsafe { list.deallocate(b, 64) };
+        assert_eq!(holes(&list, start), vec![(0, SIZE)]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_free() {
+        let (_buffer, _start, mut list) = new_list();
+        let a = list.allocate_first_fit(64, 8).unwrap();
+        list.allocate_first_fit(64, 8).unwrap();
+        unsafe {
+            list.deallocate(a, 64);
+            list.deallocate(a, 64);
+        }
+    }
+
+    #[test]
+    fn alignment_padding() {
+        let (_buffer, start, mut list) = new_list();
+        assert_eq!(allocate(&mut list, start, 8, 8), Some(0));
+
+        // the front padding becomes a hole
+        assert_eq!(allocate(&mut list, start, 64, 256), Some(256));
+        assert_eq!(holes(&list, start),
+                   vec![(16, 240), (320, SIZE - 320)]);
+
+        // the padding before 768 would be too small to become a hole
+        assert_eq!(allocate(&mut list, start, 440, 8), Some(320));
+        assert_eq!(allocate(&mut list, start, 64, 256), Some(1024));
+        assert_eq!(holes(&list, start),
+                   vec![(16, 240), (760, 264), (1088, SIZE - 1088)]);
+
+        // holes are skipped if the rest after the block would be too small to become a hole
+        assert_eq!(allocate(&mut list, start, 232, 16), Some(1088));
+        assert_eq!(holes(&list, start),
+                   vec![(16, 240), (760, 264), (1320, SIZE - 1320)]);
+    }
+
+    #[test]
+    fn grow_in_place() {
+        let (_buffer, start, mut list) = new_list();
+        let a = list.allocate_first_fit(64, 8).unwrap();
+        unsafe {
+            assert!(list.resize_in_place(a, 64, 128));
+            assert_eq!(holes(&list, start), vec![(128, SIZE - 128)]);
+
+            // the rest of the hole would be too small
+            assert!(!list.resize_in_place(a, 128, SIZE - 8));
+            // the whole hole is used
+            assert!(list.resize_in_place(a, 128, SIZE));
+            assert_eq!(holes(&list, start), vec![]);
+            assert!(list.resize_in_place(a, SIZE, 128));
+        }
+
+        // the next hole is not adjacent anymore
+        let b = list.allocate_first_fit(64, 8).unwrap();
+        assert_eq!(b as usize - start, 128);
+        unsafe {
+            assert!(!list.resize_in_place(a, 128, 192));
+            assert!(list.resize_in_place(b, 64, 256));
+        }
+        assert_eq!(holes(&list, start), vec![(384, SIZE - 384)]);
+    }
+
+    #[test]
+    fn shrink_in_place() {
+        let (_buffer, start, mut list) = new_list();
+        let a = list.allocate_first_fit(128, 8).unwrap();
+        let b = list.allocate_first_fit(64, 8).unwrap();
+        let c = list.allocate_first_fit(64, 8).unwrap();
+        unsafe {
+            // the tail becomes a new hole
+            assert!(list.resize_in_place(a, 128, 64));
+            assert_eq!(holes(&list, start), vec![(64, 64), (256, SIZE - 256)]);
+
+            // the tail is too small for a hole and no hole follows the block
+            assert!(!list.resize_in_place(b, 64, 56));
+
+            // the tail is merged with the following hole
+            assert!(list.resize_in_place(c, 64, 56));
+            assert_eq!(holes(&list, start), vec![(64, 64), (248, SIZE - 248)]);
+        }
+    }
+}
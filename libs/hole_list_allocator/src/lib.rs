@@ -9,59 +9,289 @@
 
 #![feature(allocator)]
 #![feature(const_fn)]
+#![feature(asm)]
 
-#![allocator]
+// the host tests use the system allocator
+#![cfg_attr(not(test), allocator)]
 #![no_std]
 
+// the unit tests run on the host, see `make test`
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
 use spin::Mutex;
-use linked_list_allocator::Heap;
+use core::{cmp, ptr};
 
 extern crate spin;
-extern crate linked_list_allocator;
 #[macro_use]
 extern crate lazy_static;
 
-pub const HEAP_START: usize = 0o_000_001_000_000_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+pub use slab::{SizeClassStats, SIZE_CLASS_COUNT};
+pub use stats::{HeapStats, AllocationRecord};
+#[cfg(feature = "leak-tracking")]
+pub use stats::tracking::for_each_outstanding_allocation;
+use hole::HoleList;
+use slab::SlabAllocator;
+
+mod hole;
+mod slab;
+mod stats;
+
+pub const HEAP_START: usize = 0o_177777_600_000_000_000_0000;
+/// The size of the virtual region that is reserved for the heap (a whole P4 entry).
+pub const HEAP_MAX_SIZE: usize = 512 * 1024 * 1024 * 1024; // 512 GiB
+/// The size of the heap region that must be mapped before the first allocation.
+pub const HEAP_INITIAL_SIZE: usize = 100 * 1024; // 100 KiB
+
+/// The heap grows by at least this many bytes at once.
+const HEAP_GROW_SIZE: usize = 64 * 1024; // 64 KiB
+const PAGE_SIZE: usize = 4096;
+
+/// Align upwards. Returns the smallest x with alignment `align`
+/// so that x >= addr. The alignment must be a power of 2.
+fn align_up(addr: usize, align: usize) -> usize {
+    assert!(align.is_power_of_two(), "`align` must be a power of 2");
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Maps the pages of the heap region `start..(start + size)` and returns the number of bytes that
+/// could be mapped from the beginning of the region, which is a multiple of the page size.
+///
+/// The function is called with the heap locked, so it must not allocate.
+pub type MapPagesFn = fn(start: usize, size: usize) -> usize;
+
+/// A heap that maps additional pages through a callback when it runs out of memory.
+struct GrowableHeap {
+    holes: HoleList,
+    // the end of the mapped part of the heap region
+    mapped_end: usize,
+    map_pages: Option<MapPagesFn>,
+}
+
+impl GrowableHeap {
+    fn allocate(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        // such a block never fits into the heap region and rounding up its size could overflow
+        if size > HEAP_MAX_SIZE {
+            return None;
+        }
+        if let Some(ptr) = self.holes.allocate_first_fit(size, align) {
+            return Some(ptr);
+        }
+
+        // the new region is large enough for the allocation, even if it can't be merged with a
+        // hole at the current end of the heap (the padding covers the minimal hole size)
+        let min_size = HoleList::block_size(size)
+            .checked_add(align)
+            .and_then(|min_size| min_size.checked_add(HoleList::min_size()));
+        match min_size {
+            Some(min_size) if self.grow(cmp::max(min_size, HEAP_GROW_SIZE)) => {
+                self.holes.allocate_first_fit(size, align)
+            }
+            _ => None,
+        }
+    }
+
+    /// Maps at least `by` bytes at the end of the heap and adds them to the free memory. Returns
+    /// whether the mapping succeeded.
+    fn grow(&mut self, by: usize) -> bool {
+        let map_pages = match self.map_pages {
+            Some(map_pages) => map_pages,
+            None => return false,
+        };
+        // checked before rounding up, so that huge sizes can't overflow
+        if by > HEAP_START + HEAP_MAX_SIZE - self.mapped_end {
+            return false;
+        }
+        let by = (by + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        if self.mapped_end + by > HEAP_START + HEAP_MAX_SIZE {
+            return false;
+        }
+
+        let mapped = map_pages(self.mapped_end, by);
+        if mapped > 0 {
+            // the new pages are free memory now
+            unsafe { self.holes.deallocate(self.mapped_end as *mut u8, mapped) };
+            self.mapped_end += mapped;
+        }
+        mapped == by
+    }
+}
 
 lazy_static! {
-    static ref HEAP: Mutex<Heap> = Mutex::new(unsafe {
-        Heap::new(HEAP_START, HEAP_SIZE)
+    static ref HEAP: Mutex<GrowableHeap> = Mutex::new(GrowableHeap {
+        holes: unsafe { HoleList::new(HEAP_START, HEAP_INITIAL_SIZE) },
+        mapped_end: HEAP_START + HEAP_INITIAL_SIZE,
+        map_pages: None,
     });
 }
 
-#[no_mangle]
+lazy_static! {
+    /// Serves small allocations. It allocates its slabs from `HEAP`, so it must always be locked
+    /// before `HEAP`.
+    static ref SLABS: Mutex<SlabAllocator> = Mutex::new(SlabAllocator::new());
+}
+
+/// Returns the allocation statistics of the slab allocator for each size class.
+pub fn slab_stats() -> [SizeClassStats; SIZE_CLASS_COUNT] {
+    SLABS.lock().stats()
+}
+
+/// Returns the usage counters of the heap.
+pub fn stats() -> HeapStats {
+    let mut stats = stats::get();
+    stats.heap_size = HEAP.lock().mapped_end - HEAP_START;
+    stats
+}
+
+/// Returns the return address of the current function.
+///
+/// Called in `__rust_allocate`, this is the address in its direct caller, which is usually one of
+/// liballoc's wrappers (e.g. `heap::allocate` or `RawVec`) and not the code that created the box
+/// or vector. We don't walk further up the stack because the number of wrapper frames depends on
+/// what the compiler inlined, so the recorded address has to be resolved with a debugger.
+///
+/// This relies on frame pointers. The kernel's target specification disables frame pointer
+/// elimination for all builds because a target specification can't depend on a cargo feature,
+/// and without frame pointers `rbp` is a general purpose register, so reading `[rbp + 8]` here
+/// could fault inside the allocator. Debug builds keep frame pointers for their debug info anyway,
+/// so this only costs a register in release builds.
+#[cfg(feature = "leak-tracking")]
+macro_rules! return_address {
+    () => {{
+        let address: usize;
+        unsafe { asm!("mov $0, [rbp + 8]" : "=r"(address) : : : "intel") };
+        address
+    }}
+}
+
+#[cfg(not(feature = "leak-tracking"))]
+macro_rules! return_address {
+    () => { 0 }
+}
+
+/// Enables heap growth. Until this function is called, the heap is limited to
+/// `HEAP_INITIAL_SIZE` bytes.
+pub fn set_map_pages_fn(map_pages: MapPagesFn) {
+    HEAP.lock().map_pages = Some(map_pages);
+}
+
+/// Allocates a block from the slab allocator or, if it is too large, from the hole list.
+fn allocate_block(size: usize, align: usize) -> Option<*mut u8> {
+    match slab::size_class(size, align) {
+        Some(class) => {
+            SLABS.lock().allocate(class,
+                                  || HEAP.lock().allocate(slab::SLAB_SIZE, slab::SLAB_ALIGN))
+        }
+        None => HEAP.lock().allocate(size, align),
+    }
+}
+
+/// Frees a block that was allocated through `allocate_block` with the same size and alignment.
+unsafe fn deallocate_block(ptr: *mut u8, size: usize, align: usize) {
+    match slab::size_class(size, align) {
+        Some(class) => SLABS.lock().deallocate(class, ptr),
+        None => HEAP.lock().holes.deallocate(ptr, size),
+    }
+}
+
+/// Returns the size of the block that `allocate_block` uses for an allocation. Slab objects are
+/// rounded up to their size class and hole list blocks to the hole alignment.
+fn block_size(size: usize, align: usize) -> usize {
+    match slab::size_class(size, align) {
+        Some(class) => slab::object_size(class),
+        None => HoleList::block_size(size),
+    }
+}
+
+/// Heap corruption checks. Each block is surrounded by red zones that are filled with a known
+/// pattern and a header records its size, alignment and state, so that invalid and double frees
+/// can be detected. Freed blocks are poisoned to make use-after-free bugs visible.
+#[cfg(feature = "heap-debug")]
+mod debug;
+
+/// Without the `heap-debug` feature, the blocks are handed out directly.
+#[cfg(not(feature = "heap-debug"))]
+mod debug {
+    pub use super::{allocate_block as allocate, deallocate_block as deallocate,
+                    block_size as usable_size};
+}
+
+#[cfg_attr(not(test), no_mangle)]
 pub extern fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
-    HEAP.lock().allocate_first_fit(size, align).expect("out of memory")
+    match debug::allocate(size, align) {
+        Some(ptr) => {
+            stats::record_allocation(ptr as usize,
+                                     __rust_usable_size(size, align),
+                                     return_address!());
+            ptr
+        }
+        None => {
+            // liballoc calls its OOM handler for null pointers
+            stats::record_failed_allocation();
+            ptr::null_mut()
+        }
+    }
 }
 
-#[no_mangle]
+#[cfg_attr(not(test), no_mangle)]
 pub extern fn __rust_deallocate(ptr: *mut u8, size: usize, align: usize) {
-    unsafe { HEAP.lock().deallocate(ptr, size, align) };
+    stats::record_deallocation(ptr as usize, __rust_usable_size(size, align));
+    unsafe { debug::deallocate(ptr, size, align) };
 }
 
-#[no_mangle]
-pub extern fn __rust_usable_size(size: usize, _align: usize) -> usize {
-    size
+#[cfg_attr(not(test), no_mangle)]
+pub extern fn __rust_usable_size(size: usize, align: usize) -> usize {
+    debug::usable_size(size, align)
+}
+
+/// Tries to resize the allocation at `ptr` without moving it and returns whether it succeeded.
+fn resize_in_place(ptr: *mut u8, size: usize, new_size: usize, align: usize) -> bool {
+    if cfg!(feature = "heap-debug") {
+        // the back red zone would have to move, so the block is always reallocated
+        return false;
+    }
+
+    let resized = match (slab::size_class(size, align), slab::size_class(new_size, align)) {
+        (Some(class), Some(new_class)) => class == new_class,
+        (None, None) => unsafe { HEAP.lock().holes.resize_in_place(ptr, size, new_size) },
+        _ => false, // the block needs to move between the slabs and the hole list
+    };
+    if resized {
+        stats::record_resize(ptr as usize,
+                             __rust_usable_size(size, align),
+                             __rust_usable_size(new_size, align));
+    }
+    resized
 }
 
-#[no_mangle]
-pub extern fn __rust_reallocate_inplace(_ptr: *mut u8, size: usize,
-    _new_size: usize, _align: usize) -> usize
+#[cfg_attr(not(test), no_mangle)]
+pub extern fn __rust_reallocate_inplace(ptr: *mut u8, size: usize,
+    new_size: usize, align: usize) -> usize
 {
-    size
+    if resize_in_place(ptr, size, new_size, align) {
+        __rust_usable_size(new_size, align)
+    } else {
+        __rust_usable_size(size, align)
+    }
 }
 
-#[no_mangle]
+#[cfg_attr(not(test), no_mangle)]
 pub extern fn __rust_reallocate(ptr: *mut u8, size: usize, new_size: usize,
                                 align: usize) -> *mut u8 {
-    use core::{ptr, cmp};
+    if resize_in_place(ptr, size, new_size, align) {
+        return ptr;
+    }
 
     // from: https://github.com/rust-lang/rust/blob/
     //     c66d2380a810c9a2b3dbb4f93a830b101ee49cc2/
     //     src/liballoc_system/lib.rs#L98-L101
 
     let new_ptr = __rust_allocate(new_size, align);
+    if new_ptr.is_null() {
+        // the old allocation stays valid
+        return new_ptr;
+    }
     unsafe { ptr::copy(ptr, new_ptr, cmp::min(size, new_size)) };
     __rust_deallocate(ptr, size, align);
     new_ptr
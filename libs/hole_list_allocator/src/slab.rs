@@ -0,0 +1,300 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use core::{cmp, ptr};
+
+/// The number of size classes: 8, 16, 32, ..., 4096 bytes.
+pub const SIZE_CLASS_COUNT: usize = 10;
+
+const MIN_OBJECT_SIZE: usize = 8;
+const MAX_OBJECT_SIZE: usize = 4096;
+
+/// Slabs are allocated from the hole list with this size and alignment.
+pub const SLAB_SIZE: usize = 16 * 1024;
+pub const SLAB_ALIGN: usize = 4096;
+
+/// Returns the size class for an allocation or `None` if it is too large for the slab allocator.
+///
+/// The object size of the class is a power of two that is at least as large as `size` and
+/// `align`. Slabs are page aligned, so all objects are aligned to their size.
+pub fn size_class(size: usize, align: usize) -> Option<usize> {
+    // checked first, because rounding up huge sizes to a power of two overflows
+    if size > MAX_OBJECT_SIZE || align > MAX_OBJECT_SIZE {
+        return None;
+    }
+    let object_size = cmp::max(cmp::max(size, align), MIN_OBJECT_SIZE).next_power_of_two();
+    if object_size > MAX_OBJECT_SIZE {
+        None
+    } else {
+        Some((object_size.trailing_zeros() - MIN_OBJECT_SIZE.trailing_zeros()) as usize)
+    }
+}
+
+/// Returns the object size of the given size class.
+pub fn object_size(class: usize) -> usize {
+    MIN_OBJECT_SIZE << class
+}
+
+/// Allocation statistics of a size class.
+#[derive(Debug, Clone, Copy)]
+pub struct SizeClassStats {
+    /// The size of the objects in this class in bytes.
+    pub object_size: usize,
+    /// The number of slabs that were split into objects of this class.
+    pub slabs: usize,
+    /// The number of objects that are currently allocated.
+    pub allocated_objects: usize,
+    /// The number of free objects in the slabs of this class.
+    pub free_objects: usize,
+    /// The total number of allocations in this class.
+    pub total_allocations: usize,
+}
+
+/// A free object, which stores a pointer to the next free object of its class.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// A cache of objects of a single size class.
+#[derive(Clone, Copy)]
+struct SlabCache {
+    free_list: *mut FreeObject,
+    stats: SizeClassStats,
+}
+
+impl SlabCache {
+    fn new(object_size: usize) -> SlabCache {
+        SlabCache {
+            free_list: ptr::null_mut(),
+            stats: SizeClassStats {
+                object_size: object_size,
+                slabs: 0,
+                allocated_objects: 0,
+                free_objects: 0,
+                total_allocations: 0,
+            },
+        }
+    }
+
+    fn allocate(&mut self) -> Option<*mut u8> {
+        if self.free_list.is_null() {
+            return None;
+        }
+        let object = self.free_list;
+        self.free_list = unsafe { (*object).next };
+        self.stats.free_objects -= 1;
+        self.stats.allocated_objects += 1;
+        self.stats.total_allocations += 1;
+        Some(object as *mut u8)
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8) {
+        let object = ptr as *mut FreeObject;
+        (*object).next = self.free_list;
+        self.free_list = object;
+        self.stats.allocated_objects -= 1;
+        self.stats.free_objects += 1;
+    }
+
+    /// Splits the slab at `slab` into objects and adds them to the free list.
+    unsafe fn add_slab(&mut self, slab: *mut u8) {
+        let object_size = self.stats.object_size;
+        let mut offset = SLAB_SIZE;
+        // push the objects in reverse order, so that they are handed out in ascending order
+        while offset >= object_size {
+            offset -= object_size;
+            let object = slab.offset(offset as isize) as *mut FreeObject;
+            (*object).next = self.free_list;
+            self.free_list = object;
+            self.stats.free_objects += 1;
+        }
+        self.stats.slabs += 1;
+    }
+}
+
+/// An allocator with a cache for each size class. Slabs are never returned to the hole list.
+pub struct SlabAllocator {
+    caches: [SlabCache; SIZE_CLASS_COUNT],
+}
+
+// the free lists only point to heap memory that is owned by the allocator
+unsafe impl Send for SlabAllocator {}
+
+impl SlabAllocator {
+    pub fn new() -> SlabAllocator {
+        let mut caches = [SlabCache::new(0); SIZE_CLASS_COUNT];
+        for (class, cache) in caches.iter_mut().enumerate() {
+            *cache = SlabCache::new(object_size(class));
+        }
+        SlabAllocator { caches: caches }
+    }
+
+    /// Allocates an object of the given size class. If the cache is empty, a new slab is
+    /// allocated through `allocate_slab`.
+    pub fn allocate<F>(&mut self, class: usize, allocate_slab: F) -> Option<*mut u8>
+        where F: FnOnce() -> Option<*mut u8>
+    {
+        let cache = &mut self.caches[class];
+        if let Some(ptr) = cache.allocate() {
+            return Some(ptr);
+        }
+        let slab = match allocate_slab() {
+            Some(slab) => slab,
+            None => return None,
+        };
+        unsafe { cache.add_slab(slab) };
+        cache.allocate()
+    }
+
+    /// Returns an object to the cache of its size class.
+    pub unsafe fn deallocate(&mut self, class: usize, ptr: *mut u8) {
+        self.caches[class].deallocate(ptr)
+    }
+
+    pub fn stats(&self) -> [SizeClassStats; SIZE_CLASS_COUNT] {
+        let mut stats = [self.caches[0].stats; SIZE_CLASS_COUNT];
+        for (stats, cache) in stats.iter_mut().zip(self.caches.iter()) {
+            *stats = cache.stats;
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use align_up;
+    use std::vec::Vec;
+
+    /// Simulated heap memory that hands out up to `count` page aligned slabs.
+    struct Slabs {
+        memory: Vec<u8>,
+        count: usize,
+        allocated: usize,
+    }
+
+    impl Slabs {
+        fn new(count: usize) -> Slabs {
+            Slabs {
+                // one more slab, so that there is room for the alignment
+                memory: vec![0; (count + 1) * SLAB_SIZE],
+                count: count,
+                allocated: 0,
+            }
+        }
+
+        fn allocate(&mut self) -> Option<*mut u8> {
+            if self.allocated == self.count {
+                return None;
+            }
+            let start = align_up(self.memory.as_ptr() as usize, SLAB_ALIGN);
+            let slab = start + self.allocated * SLAB_SIZE;
+            self.allocated += 1;
+            Some(slab as *mut u8)
+        }
+    }
+
+    #[test]
+    fn size_classes() {
+        assert_eq!(size_class(0, 1), Some(0));
+        assert_eq!(size_class(8, 8), Some(0));
+        assert_eq!(size_class(9, 1), Some(1));
+        assert_eq!(size_class(100, 8), Some(4));
+        assert_eq!(size_class(8, 128), Some(4));
+        assert_eq!(size_class(4096, 8), Some(9));
+        assert_eq!(size_class(8, 4096), Some(9));
+        assert_eq!(object_size(0), 8);
+        assert_eq!(object_size(4), 128);
+        assert_eq!(object_size(SIZE_CLASS_COUNT - 1), 4096);
+    }
+
+    #[test]
+    fn large_allocations_use_hole_list() {
+        assert_eq!(size_class(4097, 1), None);
+        assert_eq!(size_class(8, 8192), None);
+        assert_eq!(size_class(SLAB_SIZE, 8), None);
+        assert_eq!(size_class(usize::max_value(), 8), None);
+    }
+
+    #[test]
+    fn objects_are_aligned() {
+        let mut slabs = Slabs::new(1);
+        let mut allocator = SlabAllocator::new();
+        let class = size_class(1024, 8).unwrap();
+
+        let mut previous = 0;
+        for _ in 0..(SLAB_SIZE / 1024) {
+            let ptr = allocator.allocate(class, || slabs.allocate()).unwrap() as usize;
+            assert_eq!(ptr % 1024, 0);
+            assert!(ptr > previous);
+            previous = ptr;
+        }
+        assert_eq!(slabs.allocated, 1);
+    }
+
+    #[test]
+    fn refill() {
+        let mut slabs = Slabs::new(2);
+        let mut allocator = SlabAllocator::new();
+        let class = size_class(4096, 8).unwrap();
+        let objects_per_slab = SLAB_SIZE / 4096;
+
+        let mut objects = Vec::new();
+        for _ in 0..objects_per_slab {
+            objects.push(allocator.allocate(class, || slabs.allocate()).unwrap());
+        }
+        assert_eq!(slabs.allocated, 1);
+
+        // the cache is empty, so a second slab is split
+        objects.push(allocator.allocate(class, || slabs.allocate()).unwrap());
+        assert_eq!(slabs.allocated, 2);
+        assert_eq!(allocator.stats()[class].slabs, 2);
+
+        for _ in 1..objects_per_slab {
+            objects.push(allocator.allocate(class, || slabs.allocate()).unwrap());
+        }
+        // both slabs are used up and there are no more slabs
+        assert_eq!(allocator.allocate(class, || slabs.allocate()), None);
+
+        // freed objects are reused without a new slab
+        unsafe { allocator.deallocate(class, objects[3]) };
+        assert_eq!(allocator.allocate(class, || None), Some(objects[3]));
+    }
+
+    #[test]
+    fn stats() {
+        let mut slabs = Slabs::new(2);
+        let mut allocator = SlabAllocator::new();
+        let small = size_class(16, 8).unwrap();
+        let large = size_class(2048, 8).unwrap();
+
+        let a = allocator.allocate(small, || slabs.allocate()).unwrap();
+        allocator.allocate(small, || slabs.allocate()).unwrap();
+        allocator.allocate(large, || slabs.allocate()).unwrap();
+        unsafe { allocator.deallocate(small, a) };
+        allocator.allocate(small, || slabs.allocate()).unwrap();
+
+        let stats = allocator.stats();
+        assert_eq!(stats[small].object_size, 16);
+        assert_eq!(stats[small].slabs, 1);
+        assert_eq!(stats[small].allocated_objects, 2);
+        assert_eq!(stats[small].free_objects, SLAB_SIZE / 16 - 2);
+        assert_eq!(stats[small].total_allocations, 3);
+
+        assert_eq!(stats[large].object_size, 2048);
+        assert_eq!(stats[large].slabs, 1);
+        assert_eq!(stats[large].allocated_objects, 1);
+        assert_eq!(stats[large].free_objects, SLAB_SIZE / 2048 - 1);
+        assert_eq!(stats[large].total_allocations, 1);
+
+        // the other classes are untouched
+        assert_eq!(stats[0].slabs, 0);
+        assert_eq!(stats[0].total_allocations, 0);
+    }
+}
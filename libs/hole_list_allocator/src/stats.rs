@@ -0,0 +1,281 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use spin::Mutex;
+
+/// Usage counters of the heap.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// The number of bytes that are currently allocated, including the padding of the blocks.
+    pub bytes_allocated: usize,
+    /// The highest value of `bytes_allocated` so far.
+    pub peak_bytes_allocated: usize,
+    /// The total number of successful allocations.
+    pub allocations: usize,
+    /// The total number of deallocations.
+    pub deallocations: usize,
+    /// The number of allocations that failed because no memory was left.
+    pub failed_allocations: usize,
+    /// The number of bytes of the heap region that are currently mapped.
+    pub heap_size: usize,
+}
+
+static STATS: Mutex<HeapStats> = Mutex::new(HeapStats {
+    bytes_allocated: 0,
+    peak_bytes_allocated: 0,
+    allocations: 0,
+    deallocations: 0,
+    failed_allocations: 0,
+    heap_size: 0,
+});
+
+impl HeapStats {
+    fn add_allocation(&mut self, size: usize) {
+        self.bytes_allocated += size;
+        if self.bytes_allocated > self.peak_bytes_allocated {
+            self.peak_bytes_allocated = self.bytes_allocated;
+        }
+        self.allocations += 1;
+    }
+
+    fn add_deallocation(&mut self, size: usize) {
+        self.bytes_allocated -= size;
+        self.deallocations += 1;
+    }
+
+    fn add_resize(&mut self, size: usize, new_size: usize) {
+        self.bytes_allocated = self.bytes_allocated - size + new_size;
+        if self.bytes_allocated > self.peak_bytes_allocated {
+            self.peak_bytes_allocated = self.bytes_allocated;
+        }
+    }
+}
+
+/// Returns the current counters. The `heap_size` field is not filled in.
+pub fn get() -> HeapStats {
+    *STATS.lock()
+}
+
+pub fn record_allocation(address: usize, size: usize, call_site: usize) {
+    STATS.lock().add_allocation(size);
+    tracking::insert(address, size, call_site);
+}
+
+pub fn record_deallocation(address: usize, size: usize) {
+    STATS.lock().add_deallocation(size);
+    tracking::remove(address);
+}
+
+pub fn record_resize(address: usize, size: usize, new_size: usize) {
+    STATS.lock().add_resize(size, new_size);
+    tracking::resize(address, new_size);
+}
+
+pub fn record_failed_allocation() {
+    STATS.lock().failed_allocations += 1;
+}
+
+/// An allocation that was recorded by the leak tracker and not freed yet.
+#[derive(Debug, Clone, Copy)]
+pub struct AllocationRecord {
+    pub address: usize,
+    pub size: usize,
+    /// The return address of the `__rust_allocate` call. This is usually an address in one of
+    /// liballoc's wrappers, see `return_address!`.
+    pub call_site: usize,
+}
+
+#[cfg(feature = "leak-tracking")]
+pub mod tracking {
+    use super::AllocationRecord;
+    use spin::Mutex;
+
+    /// The maximum number of outstanding allocations that can be recorded.
+    const MAX_RECORDS: usize = 512;
+
+    const UNUSED: AllocationRecord = AllocationRecord {
+        address: 0,
+        size: 0,
+        call_site: 0,
+    };
+
+    struct Tracker {
+        // unused records have the address 0
+        records: [AllocationRecord; MAX_RECORDS],
+        // the number of outstanding allocations that didn't fit into `records`
+        untracked: usize,
+    }
+
+    static TRACKER: Mutex<Tracker> = Mutex::new(Tracker {
+        records: [UNUSED; MAX_RECORDS],
+        untracked: 0,
+    });
+
+    impl Tracker {
+        fn insert(&mut self, address: usize, size: usize, call_site: usize) {
+            match self.records.iter().position(|record| record.address == 0) {
+                Some(index) => {
+                    self.records[index] = AllocationRecord {
+                        address: address,
+                        size: size,
+                        call_site: call_site,
+                    }
+                }
+                None => self.untracked += 1,
+            }
+        }
+
+        fn remove(&mut self, address: usize) {
+            match self.records.iter().position(|record| record.address == address) {
+                Some(index) => self.records[index] = UNUSED,
+                // the allocation didn't fit into the table, or the address was never recorded
+                None => self.untracked = self.untracked.saturating_sub(1),
+            }
+        }
+
+        fn resize(&mut self, address: usize, new_size: usize) {
+            if let Some(index) = self.records.iter().position(|record| record.address == address) {
+                self.records[index].size = new_size;
+            }
+        }
+
+        fn for_each<F>(&self, mut f: F) -> usize
+            where F: FnMut(&AllocationRecord)
+        {
+            for record in self.records.iter().filter(|record| record.address != 0) {
+                f(record);
+            }
+            self.untracked
+        }
+    }
+
+    pub fn insert(address: usize, size: usize, call_site: usize) {
+        TRACKER.lock().insert(address, size, call_site);
+    }
+
+    pub fn remove(address: usize) {
+        TRACKER.lock().remove(address);
+    }
+
+    pub fn resize(address: usize, new_size: usize) {
+        TRACKER.lock().resize(address, new_size);
+    }
+
+    /// Calls `f` for each recorded outstanding allocation and returns the number of outstanding
+    /// allocations that could not be recorded because the table was full.
+    ///
+    /// The tracker is locked while `f` runs, so `f` must not allocate.
+    pub fn for_each_outstanding_allocation<F>(f: F) -> usize
+        where F: FnMut(&AllocationRecord)
+    {
+        TRACKER.lock().for_each(f)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{Tracker, UNUSED, MAX_RECORDS};
+        use std::vec::Vec;
+
+        fn tracker() -> Tracker {
+            Tracker {
+                records: [UNUSED; MAX_RECORDS],
+                untracked: 0,
+            }
+        }
+
+        fn outstanding(tracker: &Tracker) -> (Vec<(usize, usize, usize)>, usize) {
+            let mut records = Vec::new();
+            let untracked = tracker.for_each(|record| {
+                records.push((record.address, record.size, record.call_site))
+            });
+            (records, untracked)
+        }
+
+        #[test]
+        fn insert_remove_resize() {
+            let mut tracker = tracker();
+            tracker.insert(0x1000, 16, 0xa);
+            tracker.insert(0x2000, 32, 0xb);
+            tracker.resize(0x2000, 64);
+            tracker.remove(0x1000);
+            assert_eq!(outstanding(&tracker), (vec![(0x2000, 64, 0xb)], 0));
+
+            // the freed record is reused
+            tracker.insert(0x3000, 8, 0xc);
+            assert_eq!(outstanding(&tracker),
+                       (vec![(0x3000, 8, 0xc), (0x2000, 64, 0xb)], 0));
+        }
+
+        #[test]
+        fn full_table() {
+            let mut tracker = tracker();
+            for i in 0..MAX_RECORDS + 2 {
+                tracker.insert(0x1000 * (i + 1), 16, 0);
+            }
+            assert_eq!(outstanding(&tracker).1, 2);
+
+            // an untracked allocation is freed
+            tracker.remove(0x1000 * (MAX_RECORDS + 1));
+            assert_eq!(outstanding(&tracker).1, 1);
+            tracker.resize(0x1000 * (MAX_RECORDS + 2), 32);
+            tracker.remove(0x1000 * (MAX_RECORDS + 2));
+            assert_eq!(outstanding(&tracker).1, 0);
+
+            // freeing an unknown address doesn't underflow the counter
+            tracker.remove(0x1000 * (MAX_RECORDS + 3));
+            assert_eq!(outstanding(&tracker).1, 0);
+            assert_eq!(outstanding(&tracker).0.len(), MAX_RECORDS);
+        }
+    }
+}
+
+#[cfg(not(feature = "leak-tracking"))]
+mod tracking {
+    pub fn insert(_address: usize, _size: usize, _call_site: usize) {}
+
+    pub fn remove(_address: usize) {}
+
+    pub fn resize(_address: usize, _new_size: usize) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HeapStats;
+
+    fn stats() -> HeapStats {
+        HeapStats {
+            bytes_allocated: 0,
+            peak_bytes_allocated: 0,
+            allocations: 0,
+            deallocations: 0,
+            failed_allocations: 0,
+            heap_size: 0,
+        }
+    }
+
+    #[test]
+    fn counters() {
+        let mut stats = stats();
+        stats.add_allocation(64);
+        stats.add_allocation(32);
+        stats.add_deallocation(64);
+        assert_eq!(stats.bytes_allocated, 32);
+        assert_eq!(stats.peak_bytes_allocated, 96);
+        assert_eq!(stats.allocations, 2);
+        assert_eq!(stats.deallocations, 1);
+
+        stats.add_resize(32, 128);
+        assert_eq!(stats.bytes_allocated, 128);
+        assert_eq!(stats.peak_bytes_allocated, 128);
+        stats.add_resize(128, 16);
+        assert_eq!(stats.bytes_allocated, 16);
+        assert_eq!(stats.peak_bytes_allocated, 128);
+        assert_eq!(stats.allocations, 2);
+    }
+}
@@ -8,12 +8,17 @@
 ; except according to those terms.
 
 global start
+global gdt64_high_pointer
 extern long_mode_start
 
-section .text
+; The kernel is linked at this virtual offset from its physical load address. The 32-bit code
+; below runs before paging is enabled, so it must subtract it from all absolute addresses.
+KERNEL_OFFSET equ 0xffffffff80000000
+
+section .boot progbits alloc exec nowrite align=16
 bits 32
 start:
-    mov esp, stack_top
+    mov esp, stack_top - KERNEL_OFFSET
     ; Move Multiboot info pointer to edi to pass it to the kernel. We must not
     ; modify the `edi` register until the kernel it called.
     mov edi, ebx
@@ -27,7 +32,7 @@ start:
     call set_up_SSE
 
     ; load the 64-bit GDT
-    lgdt [gdt64.pointer]
+    lgdt [gdt64.pointer - KERNEL_OFFSET]
 
     ; update selectors
     mov ax, gdt64.data
@@ -38,20 +43,29 @@ start:
     jmp gdt64.code:long_mode_start
 
 set_up_page_tables:
-    ; recursive map P4
-    mov eax, p4_table
+    ; recursive map P4 (the last entry is used for the higher half)
+    mov eax, p4_table - KERNEL_OFFSET
+    or eax, 0b11 ; present + writable
+    mov [p4_table - KERNEL_OFFSET + 510 * 8], eax
+
+    ; map first P4 entry to P3 table (identity map)
+    mov eax, p3_table - KERNEL_OFFSET
     or eax, 0b11 ; present + writable
-    mov [p4_table + 511 * 8], eax
+    mov [p4_table - KERNEL_OFFSET], eax
 
-    ; map first P4 entry to P3 table
-    mov eax, p3_table
+    ; map last P4 entry to the higher half P3 table
+    mov eax, p3_high_table - KERNEL_OFFSET
     or eax, 0b11 ; present + writable
-    mov [p4_table], eax
+    mov [p4_table - KERNEL_OFFSET + 511 * 8], eax
 
     ; map first P3 entry to P2 table
-    mov eax, p2_table
+    mov eax, p2_table - KERNEL_OFFSET
     or eax, 0b11 ; present + writable
-    mov [p3_table], eax
+    mov [p3_table - KERNEL_OFFSET], eax
+
+    ; map the P3 entry for KERNEL_OFFSET to the same P2 table, so that the first GiB is
+    ; mapped both at address 0 and at KERNEL_OFFSET
+    mov [p3_high_table - KERNEL_OFFSET + 510 * 8], eax
 
     ; map each P2 entry to a huge 2MiB page
     mov ecx, 0 ; counter variable
@@ -60,7 +74,7 @@ set_up_page_tables:
     mov eax, 0x200000  ; 2MiB
     mul ecx            ; start address of ecx-th page
     or eax, 0b10000011 ; present + writable + huge
-    mov [p2_table + ecx * 8], eax ; map ecx-th entry
+    mov [p2_table - KERNEL_OFFSET + ecx * 8], eax ; map ecx-th entry
 
     inc ecx            ; increase counter
     cmp ecx, 512       ; if counter == 512, the whole P2 table is mapped
@@ -70,7 +84,7 @@ set_up_page_tables:
 
 enable_paging:
     ; load P4 to cr3 register (cpu uses this to access the P4 table)
-    mov eax, p4_table
+    mov eax, p4_table - KERNEL_OFFSET
     mov cr3, eax
 
     ; enable PAE-flag in cr4 (Physical Address Extension)
@@ -192,6 +206,8 @@ p4_table:
     resb 4096
 p3_table:
     resb 4096
+p3_high_table:
+    resb 4096
 p2_table:
     resb 4096
 stack_bottom:
@@ -205,6 +221,10 @@ gdt64:
     dq (1<<44) | (1<<47) | (1<<41) | (1<<43) | (1<<53) ; code segment
 .data: equ $ - gdt64 ; new
     dq (1<<44) | (1<<47) | (1<<41) ; data segment
-.pointer:
-    dw $ - gdt64 - 1
+.end:
+.pointer: ; uses the physical address, since the higher half is not mapped yet
+    dw .end - gdt64 - 1
+    dq gdt64 - KERNEL_OFFSET
+gdt64_high_pointer: ; used after the jump to the higher half
+    dw gdt64.end - gdt64 - 1
     dq gdt64
@@ -0,0 +1,85 @@
+; Copyright 2016 Philipp Oppermann. See the README.md
+; file at the top-level directory of this distribution.
+;
+; Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+; http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+; <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+; option. This file may not be copied, modified, or distributed
+; except according to those terms.
+
+global interrupt_stubs
+extern interrupt_dispatch
+
+section .text
+bits 64
+
+; The entry points of all 256 interrupt vectors. The CPU pushes an error code only for some
+; exceptions, so the other stubs push a dummy error code. Then the vector number is pushed, so
+; that all vectors continue in `interrupt_common` with the same stack layout.
+%assign vector 0
+%rep 256
+interrupt_stub_ %+ vector:
+%assign has_error_code vector == 8 || (vector >= 10 && vector <= 14) || vector == 17
+%assign has_error_code has_error_code || vector == 21 || vector == 29 || vector == 30
+%if !has_error_code
+    push 0 ; dummy error code
+%endif
+    push vector
+    jmp interrupt_common
+%assign vector vector + 1
+%endrep
+
+; Saves all general purpose registers and calls `interrupt_dispatch(context)`. Together with the
+; vector number, the error code and the exception stack frame they form an `InterruptContext`,
+; which the handlers can modify before it is restored.
+interrupt_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    ; the Rust code expects the direction flag to be cleared
+    cld
+
+    mov rdi, rsp ; pointer to the `InterruptContext`
+    ; the stack is 16 byte aligned here: the CPU aligns it before pushing the 5 qwords of the
+    ; exception stack frame and the context has 22 qwords in total
+    call interrupt_dispatch
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    add rsp, 16 ; pop vector number and error code
+    iretq
+
+section .rodata
+; The addresses of the stubs, indexed by vector number. Used by `Idt` to fill the gates.
+interrupt_stubs:
+%assign vector 0
+%rep 256
+    dq interrupt_stub_ %+ vector
+%assign vector vector + 1
+%endrep
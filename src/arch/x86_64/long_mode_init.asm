@@ -9,18 +9,35 @@
 
 global long_mode_start
 extern rust_main
+extern gdt64_high_pointer
 
-section .text
+KERNEL_OFFSET equ 0xffffffff80000000
+
+section .boot progbits alloc exec nowrite align=16
 bits 64
 long_mode_start:
-    ; call rust main (with multiboot pointer in rdi)
+    ; we're still running at the physical address, so jump to the higher half
+    mov rax, higher_half_start
+    jmp rax
+
+section .text
+bits 64
+higher_half_start:
+    ; move the stack pointer to the higher half, too
+    mov rax, KERNEL_OFFSET
+    add rsp, rax
+
+    ; reload the GDT through its higher half address, because the identity map is removed later
+    lgdt [gdt64_high_pointer]
+
+    ; call rust main (with the physical multiboot pointer in rdi)
     call rust_main
 .os_returned:
     ; rust main returned, print `OS returned!`
     mov rax, 0x4f724f204f534f4f
-    mov [0xb8000], rax
+    mov [KERNEL_OFFSET + 0xb8000], rax
     mov rax, 0x4f724f754f744f65
-    mov [0xb8008], rax
+    mov [KERNEL_OFFSET + 0xb8008], rax
     mov rax, 0x4f214f644f654f6e
-    mov [0xb8010], rax
+    mov [KERNEL_OFFSET + 0xb8010], rax
     hlt
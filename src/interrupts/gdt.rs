@@ -0,0 +1,162 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use bit_field::BitField;
+use core::mem::size_of;
+use x86::bits64::task::TaskStateSegment;
+use x86::shared::segmentation::SegmentSelector;
+use x86::shared::PrivilegeLevel;
+
+/// The number of 8 byte slots of the GDT. A TSS descriptor needs two slots.
+const ENTRY_COUNT: usize = 8;
+
+pub struct Gdt {
+    table: [u64; ENTRY_COUNT],
+    next_free: usize,
+}
+
+impl Gdt {
+    pub fn new() -> Gdt {
+        Gdt {
+            table: [0; ENTRY_COUNT],
+            next_free: 1, // the first entry must be the null descriptor
+        }
+    }
+
+    /// Adds the given descriptor and returns a selector for it.
+    pub fn add_entry(&mut self, entry: Descriptor) -> SegmentSelector {
+        let index = match entry {
+            Descriptor::UserSegment(value) => self.push(value),
+            Descriptor::SystemSegment(value_low, value_high) => {
+                let index = self.push(value_low);
+                self.push(value_high);
+                index
+            }
+        };
+        SegmentSelector::new(index as u16, PrivilegeLevel::Ring0)
+    }
+
+    fn push(&mut self, value: u64) -> usize {
+        if self.next_free < self.table.len() {
+            let index = self.next_free;
+            self.table[index] = value;
+            self.next_free += 1;
+            index
+        } else {
+            panic!("GDT full");
+        }
+    }
+
+    #[cfg(not(test))]
+    pub fn load(&'static self) {
+        use x86::shared::dtables::{DescriptorTablePointer, lgdt};
+
+        let ptr = DescriptorTablePointer {
+            base: self.table.as_ptr() as *const ::x86::shared::segmentation::SegmentDescriptor,
+            limit: (self.table.len() * size_of::<u64>() - 1) as u16,
+        };
+
+        unsafe { lgdt(&ptr) };
+    }
+}
+
+pub enum Descriptor {
+    UserSegment(u64),
+    SystemSegment(u64, u64),
+}
+
+impl Descriptor {
+    pub fn kernel_code_segment() -> Descriptor {
+        let flags = USER_SEGMENT | PRESENT | READ_WRITE | EXECUTABLE | LONG_MODE;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    pub fn kernel_data_segment() -> Descriptor {
+        let flags = USER_SEGMENT | PRESENT | READ_WRITE;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    #[cfg(not(test))]
+    pub fn tss_segment(tss: &'static TaskStateSegment) -> Descriptor {
+        Descriptor::tss_segment_at(tss as *const _ as u64)
+    }
+
+    fn tss_segment_at(base: u64) -> Descriptor {
+        let mut low = PRESENT.bits();
+        // base
+        low.set_range(16..40, base.get_range(0..24));
+        low.set_range(56..64, base.get_range(24..32));
+        // limit (the `-1` is needed since the bound is inclusive)
+        low.set_range(0..16, (size_of::<TaskStateSegment>() - 1) as u64);
+        // type (0b1001 = available 64-bit tss)
+        low.set_range(40..44, 0b1001);
+
+        let mut high = 0;
+        high.set_range(0..32, base.get_range(32..64));
+
+        Descriptor::SystemSegment(low, high)
+    }
+}
+
+bitflags! {
+    flags DescriptorFlags: u64 {
+        const READ_WRITE        = 1 << 41,
+        const EXECUTABLE        = 1 << 43,
+        const USER_SEGMENT      = 1 << 44,
+        const PRESENT           = 1 << 47,
+        const LONG_MODE         = 1 << 53,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Descriptor, Gdt};
+    use core::mem::size_of;
+    use x86::bits64::task::TaskStateSegment;
+
+    #[test]
+    fn segment_descriptors() {
+        // the same values as the boot GDT in boot.asm
+        match Descriptor::kernel_code_segment() {
+            Descriptor::UserSegment(value) => assert_eq!(value, 0x0020_9a00_0000_0000),
+            Descriptor::SystemSegment(..) => panic!("code segment is a user segment"),
+        }
+        match Descriptor::kernel_data_segment() {
+            Descriptor::UserSegment(value) => assert_eq!(value, 0x0000_9200_0000_0000),
+            Descriptor::SystemSegment(..) => panic!("data segment is a user segment"),
+        }
+    }
+
+    #[test]
+    fn tss_descriptor() {
+        assert_eq!(size_of::<TaskStateSegment>(), 104);
+        match Descriptor::tss_segment_at(0x1234_5678_9abc_def0) {
+            Descriptor::SystemSegment(low, high) => {
+                assert_eq!(low, 0x9a00_89bc_def0_0067);
+                assert_eq!(high, 0x1234_5678);
+            }
+            Descriptor::UserSegment(_) => panic!("TSS descriptor is a system segment"),
+        }
+    }
+
+    #[test]
+    fn selectors() {
+        let mut gdt = Gdt::new();
+        let code = gdt.add_entry(Descriptor::kernel_code_segment());
+        let data = gdt.add_entry(Descriptor::kernel_data_segment());
+        let tss = gdt.add_entry(Descriptor::tss_segment_at(0));
+        let next = gdt.add_entry(Descriptor::kernel_data_segment());
+
+        // the TSS descriptor takes two slots
+        assert_eq!(code.bits(), 0x08);
+        assert_eq!(data.bits(), 0x10);
+        assert_eq!(tss.bits(), 0x18);
+        assert_eq!(next.bits(), 0x28);
+    }
+}
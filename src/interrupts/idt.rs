@@ -7,32 +7,286 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 use x86::shared::segmentation::{self, SegmentSelector};
 use x86::shared::PrivilegeLevel;
 
-pub struct Idt([Entry; 16]);
+/// The number of entries of the IDT. Vectors 0 to 31 are used for CPU exceptions, the others
+/// for hardware and software interrupts.
+pub const ENTRY_COUNT: usize = 256;
+
+/// A handler that decides how to continue. Changes to the context are applied before.
+pub type HandlerFunc = fn(&mut InterruptContext) -> Disposition;
+/// A handler for an exception that pushes an error code.
+pub type HandlerFuncWithErrCode = fn(&mut InterruptContext, u64) -> Disposition;
+/// A handler that never returns, e.g. because the exception is not recoverable.
+pub type DivergingHandlerFunc = fn(&mut InterruptContext) -> !;
+/// A diverging handler for an exception that pushes an error code.
+pub type DivergingHandlerFuncWithErrCode = fn(&mut InterruptContext, u64) -> !;
+
+/// How to continue after a handler returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposition {
+    /// Return to the interrupted instruction, e.g. after the cause of a fault was resolved.
+    Resume,
+    /// Advance the instruction pointer by the given number of bytes to skip the faulting
+    /// instruction.
+    #[allow(dead_code)]
+    Skip(u64),
+    /// Print a crash report with the given message and halt.
+    Halt(&'static str),
+}
+
+/// An interrupt vector for which the CPU doesn't push an error code.
+#[derive(Debug, Clone, Copy)]
+pub struct Vector(u8);
+
+/// An exception vector for which the CPU pushes an error code.
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorCodeVector(u8);
+
+impl Vector {
+    /// Returns the vector of a hardware or software interrupt. Panics if `number` is one of the
+    /// vectors that are reserved for CPU exceptions.
+    #[allow(dead_code)]
+    pub fn interrupt(number: u8) -> Vector {
+        assert!(number >= 32, "vector {} is reserved for CPU exceptions", number);
+        Vector(number)
+    }
+}
+
+pub const DIVIDE_BY_ZERO: Vector = Vector(0);
+pub const DEBUG: Vector = Vector(1);
+pub const NON_MASKABLE_INTERRUPT: Vector = Vector(2);
+pub const BREAKPOINT: Vector = Vector(3);
+pub const OVERFLOW: Vector = Vector(4);
+pub const BOUND_RANGE_EXCEEDED: Vector = Vector(5);
+pub const INVALID_OPCODE: Vector = Vector(6);
+pub const DEVICE_NOT_AVAILABLE: Vector = Vector(7);
+pub const DOUBLE_FAULT: ErrorCodeVector = ErrorCodeVector(8);
+pub const COPROCESSOR_SEGMENT_OVERRUN: Vector = Vector(9);
+pub const INVALID_TSS: ErrorCodeVector = ErrorCodeVector(10);
+pub const SEGMENT_NOT_PRESENT: ErrorCodeVector = ErrorCodeVector(11);
+pub const STACK_SEGMENT_FAULT: ErrorCodeVector = ErrorCodeVector(12);
+pub const GENERAL_PROTECTION_FAULT: ErrorCodeVector = ErrorCodeVector(13);
+pub const PAGE_FAULT: ErrorCodeVector = ErrorCodeVector(14);
+pub const X87_FLOATING_POINT: Vector = Vector(16);
+pub const ALIGNMENT_CHECK: ErrorCodeVector = ErrorCodeVector(17);
+pub const MACHINE_CHECK: Vector = Vector(18);
+pub const SIMD_FLOATING_POINT: Vector = Vector(19);
+pub const VIRTUALIZATION: Vector = Vector(20);
+pub const CONTROL_PROTECTION: ErrorCodeVector = ErrorCodeVector(21);
+pub const HYPERVISOR_INJECTION: Vector = Vector(28);
+pub const VMM_COMMUNICATION: ErrorCodeVector = ErrorCodeVector(29);
+pub const SECURITY_EXCEPTION: ErrorCodeVector = ErrorCodeVector(30);
+
+/// The exception vectors that are reserved by the architecture.
+pub const RESERVED: [Vector; 8] = [Vector(15),
+                                   Vector(22),
+                                   Vector(23),
+                                   Vector(24),
+                                   Vector(25),
+                                   Vector(26),
+                                   Vector(27),
+                                   Vector(31)];
+
+/// The state of the interrupted code as it is saved by `interrupts.asm`. It is restored when the
+/// handler returns, so handlers can modify it, e.g. to skip an instruction or to switch to
+/// another thread.
+///
+/// The kernel is compiled without SSE, so the SSE registers don't need to be saved.
+#[derive(Debug)]
+#[repr(C)]
+pub struct InterruptContext {
+    pub registers: Registers,
+    pub vector: u64,
+    /// The error code pushed by the CPU or 0 if the exception doesn't push one.
+    pub error_code: u64,
+    pub stack_frame: ExceptionStackFrame,
+}
+
+impl InterruptContext {
+    /// Advances the instruction pointer of the interrupted code by `length` bytes.
+    fn skip(&mut self, length: u64) {
+        self.stack_frame.instruction_pointer += length;
+    }
+}
+
+/// The general purpose registers in reverse push order.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f,
+                 "rax {:#018x} rbx {:#018x} rcx {:#018x}",
+                 self.rax,
+                 self.rbx,
+                 self.rcx)?;
+        writeln!(f,
+                 "rdx {:#018x} rsi {:#018x} rdi {:#018x}",
+                 self.rdx,
+                 self.rsi,
+                 self.rdi)?;
+        writeln!(f,
+                 "rbp {:#018x} r8  {:#018x} r9  {:#018x}",
+                 self.rbp,
+                 self.r8,
+                 self.r9)?;
+        writeln!(f,
+                 "r10 {:#018x} r11 {:#018x} r12 {:#018x}",
+                 self.r10,
+                 self.r11,
+                 self.r12)?;
+        write!(f,
+               "r13 {:#018x} r14 {:#018x} r15 {:#018x}",
+               self.r13,
+               self.r14,
+               self.r15)
+    }
+}
+
+/// The frame that the CPU pushes on the stack for every interrupt.
+#[derive(Debug)]
+#[repr(C)]
+pub struct ExceptionStackFrame {
+    pub instruction_pointer: u64,
+    pub code_segment: u64,
+    pub cpu_flags: u64,
+    pub stack_pointer: u64,
+    pub stack_segment: u64,
+}
+
+/// All gates point to the stubs in `interrupts.asm`, which call `interrupt_dispatch`. It looks
+/// up the actual handler in `handlers`.
+pub struct Idt {
+    entries: [Entry; ENTRY_COUNT],
+    handlers: [Handler; ENTRY_COUNT],
+}
 
 impl Idt {
     pub fn new() -> Idt {
-        Idt([Entry::missing(); 16])
+        Idt {
+            entries: [Entry::missing(); ENTRY_COUNT],
+            handlers: [Handler::Missing; ENTRY_COUNT],
+        }
+    }
+
+    pub fn set_handler(&mut self, vector: Vector, handler: HandlerFunc) -> &mut EntryOptions {
+        self.set(vector.0, Handler::Normal(handler))
+    }
+
+    pub fn set_handler_with_error_code(&mut self,
+                                       vector: ErrorCodeVector,
+                                       handler: HandlerFuncWithErrCode)
+                                       -> &mut EntryOptions {
+        self.set(vector.0, Handler::WithErrCode(handler))
+    }
+
+    pub fn set_diverging_handler(&mut self,
+                                 vector: Vector,
+                                 handler: DivergingHandlerFunc)
+                                 -> &mut EntryOptions {
+        self.set(vector.0, Handler::Diverging(handler))
+    }
+
+    pub fn set_diverging_handler_with_error_code(&mut self,
+                                                 vector: ErrorCodeVector,
+                                                 handler: DivergingHandlerFuncWithErrCode)
+                                                 -> &mut EntryOptions {
+        self.set(vector.0, Handler::DivergingWithErrCode(handler))
     }
 
-    pub fn set_handler(&mut self, entry: u8, handler: HandlerFunc) -> &mut EntryOptions {
-        self.0[entry as usize] = Entry::new(segmentation::cs(), handler);
-        &mut self.0[entry as usize].options
+    fn set(&mut self, vector: u8, handler: Handler) -> &mut EntryOptions {
+        let index = vector as usize;
+        self.entries[index] = Entry::new(segmentation::cs(), stub_address(vector));
+        self.handlers[index] = handler;
+        &mut self.entries[index].options
     }
 
+    #[cfg(not(test))]
     pub fn load(&'static self) {
         use x86::shared::dtables::{DescriptorTablePointer, lidt};
         use core::mem::size_of;
 
         let ptr = DescriptorTablePointer {
-            base: self as *const _ as *const ::x86::bits64::irq::IdtEntry,
-            limit: (size_of::<Self>() - 1) as u16,
+            base: self.entries.as_ptr() as *const ::x86::bits64::irq::IdtEntry,
+            limit: (size_of::<[Entry; ENTRY_COUNT]>() - 1) as u16,
         };
 
+        LOADED_IDT.store(self as *const _ as usize, Ordering::SeqCst);
         unsafe { lidt(&ptr) };
     }
+
+    /// Calls the handler of `context.vector` with the matching arguments.
+    fn dispatch(&self, context: &mut InterruptContext) -> Disposition {
+        let error_code = context.error_code;
+        match self.handlers[context.vector as usize] {
+            Handler::Missing => panic!("no handler for vector {}", context.vector),
+            Handler::Normal(handler) => handler(context),
+            Handler::WithErrCode(handler) => handler(context, error_code),
+            Handler::Diverging(handler) => handler(context),
+            Handler::DivergingWithErrCode(handler) => handler(context, error_code),
+        }
+    }
+}
+
+/// The address of the IDT that was loaded last.
+static LOADED_IDT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Called by the stubs in `interrupts.asm` for every interrupt.
+#[no_mangle]
+pub extern "C" fn interrupt_dispatch(context: &mut InterruptContext) {
+    let idt = LOADED_IDT.load(Ordering::SeqCst) as *const Idt;
+    // only vectors with a handler have a present gate
+    let disposition = unsafe { (*idt).dispatch(context) };
+    match disposition {
+        Disposition::Resume => {}
+        Disposition::Skip(length) => context.skip(length),
+        Disposition::Halt(message) => super::crash(context, message),
+    }
+}
+
+#[cfg(not(test))]
+fn stub_address(vector: u8) -> u64 {
+    extern "C" {
+        static interrupt_stubs: [u64; ENTRY_COUNT];
+    }
+    unsafe { interrupt_stubs[vector as usize] }
+}
+
+// the stubs are not assembled for the host tests
+#[cfg(test)]
+fn stub_address(_vector: u8) -> u64 {
+    0
+}
+
+/// The handler function of a vector. The variant determines the signature.
+#[derive(Clone, Copy)]
+enum Handler {
+    Missing,
+    Normal(HandlerFunc),
+    WithErrCode(HandlerFuncWithErrCode),
+    Diverging(DivergingHandlerFunc),
+    DivergingWithErrCode(DivergingHandlerFuncWithErrCode),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -46,11 +300,8 @@ pub struct Entry {
     reserved: u32,
 }
 
-pub type HandlerFunc = extern "C" fn() -> !;
-
 impl Entry {
-    fn new(gdt_selector: SegmentSelector, handler: HandlerFunc) -> Self {
-        let pointer = handler as u64;
+    fn new(gdt_selector: SegmentSelector, pointer: u64) -> Self {
         Entry {
             gdt_selector: gdt_selector,
             pointer_low: pointer as u16,
@@ -107,9 +358,198 @@ impl EntryOptions {
         self
     }
 
-    #[allow(dead_code)]
     pub fn set_stack_index(&mut self, index: u16) -> &mut Self {
         self.0.set_range(0..3, index);
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::size_of;
+    use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+    #[test]
+    fn entry_size() {
+        assert_eq!(size_of::<super::Entry>(), 16);
+    }
+
+    #[test]
+    fn idt_size() {
+        // the limit of the IDT pointer must fit into an u16
+        let size = size_of::<[super::Entry; ENTRY_COUNT]>();
+        assert_eq!(size, ENTRY_COUNT * 16);
+        assert!(size - 1 <= u16::max_value() as usize);
+    }
+
+    #[test]
+    fn options_bits() {
+        // only the 'must-be-one' bits of the type field are set
+        assert_eq!(EntryOptions::minimal().0, 0x0e00);
+        // present interrupt gate
+        assert_eq!(EntryOptions::new().0, 0x8e00);
+        // trap gate
+        assert_eq!(EntryOptions::new().disable_interrupts(false).0, 0x8f00);
+        assert_eq!(EntryOptions::new().set_present(false).0, 0x0e00);
+        assert_eq!(EntryOptions::new().set_privilege_level(3).0, 0xee00);
+        assert_eq!(EntryOptions::new().set_stack_index(5).0, 0x8e05);
+    }
+
+    #[test]
+    fn context_layout() {
+        // the layout must match the push order in `interrupts.asm`
+        assert_eq!(size_of::<Registers>(), 15 * 8);
+        assert_eq!(size_of::<InterruptContext>(), 22 * 8);
+
+        let context = context(3, 0, 0);
+        let base = &context as *const _ as usize;
+        assert_eq!(&context.registers.rax as *const _ as usize - base, 14 * 8);
+        assert_eq!(&context.vector as *const _ as usize - base, 15 * 8);
+        assert_eq!(&context.error_code as *const _ as usize - base, 16 * 8);
+        assert_eq!(&context.stack_frame as *const _ as usize - base, 17 * 8);
+    }
+
+    static LAST_CALL: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    fn handler(context: &mut InterruptContext) -> Disposition {
+        LAST_CALL.store(context.stack_frame.instruction_pointer as usize, Ordering::SeqCst);
+        context.registers.rax = 42;
+        Disposition::Resume
+    }
+
+    fn handler_with_error_code(context: &mut InterruptContext, error_code: u64) -> Disposition {
+        LAST_CALL.store((context.stack_frame.instruction_pointer + error_code) as usize,
+                        Ordering::SeqCst);
+        Disposition::Halt("GENERAL PROTECTION FAULT")
+    }
+
+    fn diverging_handler(context: &mut InterruptContext) -> ! {
+        panic!("diverging handler called at {:#x}",
+               context.stack_frame.instruction_pointer);
+    }
+
+    fn diverging_handler_with_error_code(_context: &mut InterruptContext, error_code: u64) -> ! {
+        panic!("diverging handler called with error code {:#x}", error_code);
+    }
+
+    fn context(vector: u64, error_code: u64, instruction_pointer: u64) -> InterruptContext {
+        InterruptContext {
+            registers: Registers::default(),
+            vector: vector,
+            error_code: error_code,
+            stack_frame: ExceptionStackFrame {
+                instruction_pointer: instruction_pointer,
+                code_segment: 0x8,
+                cpu_flags: 0,
+                stack_pointer: 0,
+                stack_segment: 0,
+            },
+        }
+    }
+
+    #[test]
+    fn dispatch() {
+        let mut idt = Idt::new();
+        idt.set_handler(BREAKPOINT, handler);
+        idt.set_handler_with_error_code(GENERAL_PROTECTION_FAULT, handler_with_error_code);
+        assert_eq!(idt.entries[3].options.0, 0x8e00);
+        assert_eq!(idt.entries[4].options.0, 0x0e00);
+
+        let mut breakpoint = context(3, 0, 0x1000);
+        assert_eq!(idt.dispatch(&mut breakpoint), Disposition::Resume);
+        assert_eq!(LAST_CALL.load(Ordering::SeqCst), 0x1000);
+        // changes to the context are visible to the caller
+        assert_eq!(breakpoint.registers.rax, 42);
+
+        assert_eq!(idt.dispatch(&mut context(13, 0x20, 0x2000)),
+                   Disposition::Halt("GENERAL PROTECTION FAULT"));
+        assert_eq!(LAST_CALL.load(Ordering::SeqCst), 0x2020);
+    }
+
+    #[test]
+    fn skip_instruction() {
+        let mut context = context(6, 0, 0x1000);
+        context.skip(2);
+        assert_eq!(context.stack_frame.instruction_pointer, 0x1002);
+    }
+
+    #[test]
+    fn register_dump() {
+        use std::string::ToString;
+
+        let mut registers = Registers::default();
+        registers.rax = 0xdead_beef;
+        registers.r15 = 1;
+        let dump = registers.to_string();
+        assert_eq!(dump.lines().count(), 5);
+        assert!(dump.starts_with("rax 0x00000000deadbeef rbx 0x0000000000000000"));
+        assert!(dump.ends_with("r15 0x0000000000000001"));
+    }
+
+    #[test]
+    #[should_panic(expected = "diverging handler called at 0x1000")]
+    fn dispatch_diverging() {
+        let mut idt = Idt::new();
+        idt.set_diverging_handler(MACHINE_CHECK, diverging_handler);
+        idt.dispatch(&mut context(18, 0, 0x1000));
+    }
+
+    #[test]
+    #[should_panic(expected = "diverging handler called with error code 0x0")]
+    fn dispatch_diverging_with_error_code() {
+        let mut idt = Idt::new();
+        idt.set_diverging_handler_with_error_code(DOUBLE_FAULT, diverging_handler_with_error_code);
+        idt.dispatch(&mut context(8, 0, 0x1000));
+    }
+
+    #[test]
+    #[should_panic]
+    fn dispatch_missing() {
+        Idt::new().dispatch(&mut context(4, 0, 0));
+    }
+
+    #[test]
+    #[should_panic]
+    fn exception_vector_as_interrupt() {
+        Vector::interrupt(13);
+    }
+
+    #[test]
+    fn exception_vectors() {
+        // every exception vector is defined or reserved exactly once
+        let vectors = [DIVIDE_BY_ZERO,
+                       DEBUG,
+                       NON_MASKABLE_INTERRUPT,
+                       BREAKPOINT,
+                       OVERFLOW,
+                       BOUND_RANGE_EXCEEDED,
+                       INVALID_OPCODE,
+                       DEVICE_NOT_AVAILABLE,
+                       COPROCESSOR_SEGMENT_OVERRUN,
+                       X87_FLOATING_POINT,
+                       MACHINE_CHECK,
+                       SIMD_FLOATING_POINT,
+                       VIRTUALIZATION,
+                       HYPERVISOR_INJECTION];
+        let error_code_vectors = [DOUBLE_FAULT,
+                                  INVALID_TSS,
+                                  SEGMENT_NOT_PRESENT,
+                                  STACK_SEGMENT_FAULT,
+                                  GENERAL_PROTECTION_FAULT,
+                                  PAGE_FAULT,
+                                  ALIGNMENT_CHECK,
+                                  CONTROL_PROTECTION,
+                                  VMM_COMMUNICATION,
+                                  SECURITY_EXCEPTION];
+
+        let mut counts = [0; 32];
+        for vector in vectors.iter().chain(RESERVED.iter()) {
+            counts[vector.0 as usize] += 1;
+        }
+        for vector in error_code_vectors.iter() {
+            counts[vector.0 as usize] += 1;
+        }
+        assert!(counts.iter().all(|&count| count == 1));
+    }
+}
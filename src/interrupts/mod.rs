@@ -7,132 +7,223 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+#[cfg(not(test))]
+use memory::MemoryController;
+use memory::VirtAddr;
+use spin::Mutex;
+#[cfg(not(test))]
+use spin::Once;
+#[cfg(not(test))]
+use x86::bits64::task::TaskStateSegment;
+
+mod gdt;
 mod idt;
 
-macro_rules! save_scratch_registers {
-    () => {
-        asm!("push rax
-              push rcx
-              push rdx
-              push rsi
-              push rdi
-              push r8
-              push r9
-              push r10
-              push r11
-        " :::: "intel", "volatile");
-    }
-}
+#[cfg(not(test))]
+use self::idt::Disposition;
+use self::idt::InterruptContext;
 
-macro_rules! restore_scratch_registers {
-    () => {
-        asm!("pop r11
-              pop r10
-              pop r9
-              pop r8
-              pop rdi
-              pop rsi
-              pop rdx
-              pop rcx
-              pop rax
-            " :::: "intel", "volatile");
-    }
-}
+/// The IST indices of the exceptions that run on their own stack, so that they can be handled
+/// even if the kernel stack overflowed. Index 0 means "no IST stack" in an IDT entry, so the
+/// first TSS slot has index 1.
+#[cfg(not(test))]
+const DOUBLE_FAULT_IST_INDEX: u16 = 1;
+#[cfg(not(test))]
+const NMI_IST_INDEX: u16 = 2;
+#[cfg(not(test))]
+const MACHINE_CHECK_IST_INDEX: u16 = 3;
 
-macro_rules! handler {
-    ($name: ident) => {{
-        #[naked]
-        extern "C" fn wrapper() -> ! {
-            unsafe {
-                save_scratch_registers!();
-                asm!("mov rdi, rsp
-                      add rdi, 9*8 // calculate exception stack frame pointer
-                      call $0"
-                      :: "i"($name as extern "C" fn(
-                          &ExceptionStackFrame))
-                      : "rdi" : "intel");
-
-                restore_scratch_registers!();
-                asm!("iretq" :::: "intel", "volatile");
-                ::core::intrinsics::unreachable();
-            }
+/// The size of each IST stack in pages.
+#[cfg(not(test))]
+const IST_STACK_PAGES: usize = 4;
+
+/// Defines a handler that halts with a crash report.
+macro_rules! fatal_handler {
+    ($name: ident, $message: expr) => {
+        #[cfg(not(test))]
+        fn $name(_context: &mut InterruptContext) -> Disposition {
+            Disposition::Halt($message)
         }
-        wrapper
-    }}
+    }
 }
 
-macro_rules! handler_with_error_code {
-    ($name: ident) => {{
-        #[naked]
-        extern "C" fn wrapper() -> ! {
-            unsafe {
-                save_scratch_registers!();
-                asm!("mov rsi, [rsp + 9*8] // load error code into rsi
-                      mov rdi, rsp
-                      add rdi, 10*8 // calculate exception stack frame pointer
-                      sub rsp, 8 // align the stack pointer
-                      call $0
-                      add rsp, 8 // undo stack pointer alignment
-                      " :: "i"($name as extern "C" fn(
-                          &ExceptionStackFrame, u64))
-                      : "rdi","rsi" : "intel");
-                restore_scratch_registers!();
-                asm!("add rsp, 8 // pop error code
-                      iretq" :::: "intel", "volatile");
-                ::core::intrinsics::unreachable();
-            }
+/// Like `fatal_handler`, but for exceptions that push an error code.
+macro_rules! fatal_handler_with_error_code {
+    ($name: ident, $message: expr) => {
+        #[cfg(not(test))]
+        fn $name(_context: &mut InterruptContext, _error_code: u64) -> Disposition {
+            Disposition::Halt($message)
         }
-        wrapper
-    }}
+    }
 }
 
+#[cfg(not(test))]
 lazy_static! {
     static ref IDT: idt::Idt = {
         let mut idt = idt::Idt::new();
 
-        idt.set_handler(0, handler!(divide_by_zero_handler));
-        idt.set_handler(3, handler!(breakpoint_handler));
-        idt.set_handler(6, handler!(invalid_opcode_handler));
-        idt.set_handler(14, handler_with_error_code!(page_fault_handler));
+        idt.set_handler(idt::DIVIDE_BY_ZERO, divide_by_zero_handler);
+        idt.set_handler(idt::DEBUG, debug_handler);
+        idt.set_handler(idt::NON_MASKABLE_INTERRUPT, non_maskable_interrupt_handler)
+            .set_stack_index(NMI_IST_INDEX);
+        idt.set_handler(idt::BREAKPOINT, breakpoint_handler);
+        idt.set_handler(idt::OVERFLOW, overflow_handler);
+        idt.set_handler(idt::BOUND_RANGE_EXCEEDED, bound_range_exceeded_handler);
+        idt.set_handler(idt::INVALID_OPCODE, invalid_opcode_handler);
+        idt.set_handler(idt::DEVICE_NOT_AVAILABLE, device_not_available_handler);
+        idt.set_diverging_handler_with_error_code(idt::DOUBLE_FAULT, double_fault_handler)
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+        idt.set_handler(idt::COPROCESSOR_SEGMENT_OVERRUN,
+                        coprocessor_segment_overrun_handler);
+        idt.set_handler_with_error_code(idt::INVALID_TSS, invalid_tss_handler);
+        idt.set_handler_with_error_code(idt::SEGMENT_NOT_PRESENT, segment_not_present_handler);
+        idt.set_handler_with_error_code(idt::STACK_SEGMENT_FAULT, stack_segment_fault_handler);
+        idt.set_handler_with_error_code(idt::GENERAL_PROTECTION_FAULT,
+                                        general_protection_fault_handler);
+        idt.set_handler_with_error_code(idt::PAGE_FAULT, page_fault_handler);
+        idt.set_handler(idt::X87_FLOATING_POINT, x87_floating_point_handler);
+        idt.set_handler_with_error_code(idt::ALIGNMENT_CHECK, alignment_check_handler);
+        idt.set_diverging_handler(idt::MACHINE_CHECK, machine_check_handler)
+            .set_stack_index(MACHINE_CHECK_IST_INDEX);
+        idt.set_handler(idt::SIMD_FLOATING_POINT, simd_floating_point_handler);
+        idt.set_handler(idt::VIRTUALIZATION, virtualization_handler);
+        idt.set_handler_with_error_code(idt::CONTROL_PROTECTION, control_protection_handler);
+        idt.set_handler(idt::HYPERVISOR_INJECTION, hypervisor_injection_handler);
+        idt.set_handler_with_error_code(idt::VMM_COMMUNICATION, vmm_communication_handler);
+        idt.set_handler_with_error_code(idt::SECURITY_EXCEPTION, security_exception_handler);
+
+        for &vector in &idt::RESERVED {
+            idt.set_handler(vector, reserved_exception_handler);
+        }
 
         idt
     };
 }
 
-pub fn init() {
+#[cfg(not(test))]
+static TSS: Once<TaskStateSegment> = Once::new();
+#[cfg(not(test))]
+static GDT: Once<gdt::Gdt> = Once::new();
+
+/// Loads a GDT with a TSS that contains the IST stacks and loads the IDT afterwards.
+#[cfg(not(test))]
+pub fn init(memory_controller: &mut MemoryController) {
+    use x86::shared::segmentation::set_cs;
+    use x86::shared::task::load_tr;
+
+    assert_has_not_been_called!("interrupts::init must be called only once");
+
+    let tss = TSS.call_once(|| {
+        let mut tss = TaskStateSegment::new();
+        for &index in &[DOUBLE_FAULT_IST_INDEX, NMI_IST_INDEX, MACHINE_CHECK_IST_INDEX] {
+            let stack = memory_controller.alloc_stack(IST_STACK_PAGES)
+                .expect("could not allocate IST stack");
+            tss.ist[index as usize - 1] = stack.top().as_u64();
+        }
+        tss
+    });
+
+    let mut code_selector = None;
+    let mut data_selector = None;
+    let mut tss_selector = None;
+    let gdt = GDT.call_once(|| {
+        let mut gdt = gdt::Gdt::new();
+        code_selector = Some(gdt.add_entry(gdt::Descriptor::kernel_code_segment()));
+        data_selector = Some(gdt.add_entry(gdt::Descriptor::kernel_data_segment()));
+        tss_selector = Some(gdt.add_entry(gdt::Descriptor::tss_segment(tss)));
+        gdt
+    });
+    gdt.load();
+
+    unsafe {
+        // reload the segment registers, since they still refer to the boot GDT
+        set_cs(code_selector.unwrap());
+        asm!("mov ss, $0
+              mov ds, $0
+              mov es, $0"
+             :
+             : "r"(data_selector.unwrap().bits())
+             :
+             : "intel", "volatile");
+        // load the TSS
+        load_tr(tss_selector.unwrap());
+    }
+
     IDT.load();
 }
 
-#[derive(Debug)]
-#[repr(C)]
-struct ExceptionStackFrame {
-    instruction_pointer: u64,
-    code_segment: u64,
-    cpu_flags: u64,
-    stack_pointer: u64,
-    stack_segment: u64,
+/// Prints a crash report for the interrupted code and halts the CPU.
+fn crash(context: &InterruptContext, message: &str) -> ! {
+    // the exception might have interrupted a `println!`, so take the writer lock by force instead
+    // of deadlocking on it. The interrupted code never runs again.
+    unsafe { ::vga_buffer::WRITER.force_unlock() };
+    let stack_frame = &context.stack_frame;
+    println!("\nEXCEPTION: {} at {:#x} (vector {}, error code {:#x})",
+             message,
+             stack_frame.instruction_pointer,
+             context.vector,
+             context.error_code);
+    println!("rsp {:#018x} rflags {:#018x} cs {:#x} ss {:#x}",
+             stack_frame.stack_pointer,
+             stack_frame.cpu_flags,
+             stack_frame.code_segment,
+             stack_frame.stack_segment);
+    println!("{}", context.registers);
+    ::hlt_loop()
 }
 
-extern "C" fn divide_by_zero_handler(stack_frame: &ExceptionStackFrame) {
-    println!("\nEXCEPTION: DIVIDE BY ZERO\n{:#?}", stack_frame);
-    loop {}
+fatal_handler!(divide_by_zero_handler, "DIVIDE BY ZERO");
+fatal_handler!(non_maskable_interrupt_handler, "NON-MASKABLE INTERRUPT");
+fatal_handler!(overflow_handler, "OVERFLOW");
+fatal_handler!(bound_range_exceeded_handler, "BOUND RANGE EXCEEDED");
+fatal_handler!(invalid_opcode_handler, "INVALID OPCODE");
+fatal_handler!(device_not_available_handler, "DEVICE NOT AVAILABLE");
+fatal_handler!(coprocessor_segment_overrun_handler, "COPROCESSOR SEGMENT OVERRUN");
+fatal_handler_with_error_code!(invalid_tss_handler, "INVALID TSS");
+fatal_handler_with_error_code!(segment_not_present_handler, "SEGMENT NOT PRESENT");
+fatal_handler_with_error_code!(stack_segment_fault_handler, "STACK SEGMENT FAULT");
+fatal_handler_with_error_code!(general_protection_fault_handler, "GENERAL PROTECTION FAULT");
+fatal_handler!(x87_floating_point_handler, "x87 FLOATING POINT");
+fatal_handler_with_error_code!(alignment_check_handler, "ALIGNMENT CHECK");
+fatal_handler!(simd_floating_point_handler, "SIMD FLOATING POINT");
+fatal_handler!(virtualization_handler, "VIRTUALIZATION");
+fatal_handler_with_error_code!(control_protection_handler, "CONTROL PROTECTION");
+fatal_handler!(hypervisor_injection_handler, "HYPERVISOR INJECTION");
+fatal_handler_with_error_code!(vmm_communication_handler, "VMM COMMUNICATION");
+fatal_handler_with_error_code!(security_exception_handler, "SECURITY EXCEPTION");
+fatal_handler!(reserved_exception_handler, "RESERVED EXCEPTION");
+
+// returning from a double fault or a machine check is not allowed
+
+#[cfg(not(test))]
+fn double_fault_handler(context: &mut InterruptContext, _error_code: u64) -> ! {
+    crash(context, "DOUBLE FAULT")
 }
 
-extern "C" fn breakpoint_handler(stack_frame: &ExceptionStackFrame) {
-    println!("\nEXCEPTION: BREAKPOINT at {:#x}\n{:#?}",
-             stack_frame.instruction_pointer,
-             stack_frame);
+#[cfg(not(test))]
+fn machine_check_handler(context: &mut InterruptContext) -> ! {
+    crash(context, "MACHINE CHECK")
 }
 
-extern "C" fn invalid_opcode_handler(stack_frame: &ExceptionStackFrame) {
-    println!("\nEXCEPTION: INVALID OPCODE at {:#x}\n{:#?}",
-             stack_frame.instruction_pointer,
-             stack_frame);
-    loop {}
+#[cfg(not(test))]
+fn debug_handler(context: &mut InterruptContext) -> Disposition {
+    println!("\nEXCEPTION: DEBUG at {:#x}\n{:#?}",
+             context.stack_frame.instruction_pointer,
+             context.stack_frame);
+    Disposition::Resume
+}
+
+#[cfg(not(test))]
+fn breakpoint_handler(context: &mut InterruptContext) -> Disposition {
+    // `int3` is a trap, so the instruction pointer already points to the next instruction
+    println!("\nEXCEPTION: BREAKPOINT at {:#x}\n{:#?}",
+             context.stack_frame.instruction_pointer,
+             context.stack_frame);
+    Disposition::Resume
 }
 
 bitflags! {
-    flags PageFaultErrorCode: u64 {
+    pub flags PageFaultErrorCode: u64 {
         const PROTECTION_VIOLATION = 1 << 0,
         const CAUSED_BY_WRITE = 1 << 1,
         const USER_MODE = 1 << 2,
@@ -141,12 +232,34 @@ bitflags! {
     }
 }
 
-extern "C" fn page_fault_handler(stack_frame: &ExceptionStackFrame, error_code: u64) {
+/// Tries to resolve a page fault at the given address, e.g. by mapping the page. Returns `true`
+/// if the faulting instruction can be retried.
+pub type FaultResolver = fn(address: VirtAddr, error_code: PageFaultErrorCode) -> bool;
+
+static FAULT_RESOLVER: Mutex<Option<FaultResolver>> = Mutex::new(None);
+
+/// Registers a function that the page fault handler calls before it halts.
+#[allow(dead_code)]
+pub fn set_fault_resolver(resolver: FaultResolver) {
+    *FAULT_RESOLVER.lock() = Some(resolver);
+}
+
+#[cfg(not(test))]
+fn page_fault_handler(context: &mut InterruptContext, error_code: u64) -> Disposition {
     use x86::shared::control_regs;
-    println!("\nEXCEPTION: PAGE FAULT while accessing {:#x}\nerror code: \
-                                  {:?}\n{:#?}",
-             unsafe { control_regs::cr2() },
-             PageFaultErrorCode::from_bits(error_code).unwrap(),
-             stack_frame);
-    loop {}
+
+    let address = VirtAddr::new(unsafe { control_regs::cr2() });
+    let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+
+    let resolver = *FAULT_RESOLVER.lock();
+    if let Some(resolver) = resolver {
+        if resolver(address, error_code) {
+            return Disposition::Resume;
+        }
+    }
+
+    println!("\npage fault while accessing {:#x}: {:?}",
+             address,
+             error_code);
+    Disposition::Halt("PAGE FAULT")
 }
@@ -11,10 +11,15 @@
 #![feature(const_fn, unique)]
 #![feature(alloc, collections)]
 #![feature(asm)]
-#![feature(naked_functions)]
 #![feature(core_intrinsics)]
 #![no_std]
 
+// the unit tests run on the host, see `make test`
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+#[cfg(not(test))]
 extern crate rlibc;
 extern crate volatile;
 extern crate spin;
@@ -29,7 +34,10 @@ extern crate bit_field;
 #[macro_use]
 extern crate lazy_static;
 
+// the kernel heap can't be used on the host, so the tests use the system allocator
+#[cfg(not(test))]
 extern crate hole_list_allocator;
+extern crate bump_allocator;
 extern crate alloc;
 #[macro_use]
 extern crate collections;
@@ -40,29 +48,78 @@ mod memory;
 
 mod interrupts;
 
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn rust_main(multiboot_information_address: usize) {
-    // ATTENTION: we have a very small stack and no guard page
+    // ATTENTION: we have a very small boot stack until we switch to the kernel stack below
     vga_buffer::clear_screen();
     println!("Hello World{}", "!");
 
-    let boot_info = unsafe { multiboot2::load(multiboot_information_address) };
+    // the boot code maps the first GiB of physical memory to the higher half, too
+    let boot_info = unsafe {
+        multiboot2::load(multiboot_information_address + memory::KERNEL_OFFSET)
+    };
     enable_nxe_bit();
     enable_write_protect_bit();
 
     // set up guard page and map the heap pages
-    memory::init(boot_info);
+    let mut memory_controller = memory::init(boot_info);
+
+    // allocate a larger kernel stack with a guard page and continue on it
+    let kernel_stack = memory_controller.alloc_stack(memory::KERNEL_STACK_PAGES)
+        .expect("failed to allocate kernel stack");
+    println!("kernel stack: {:#x}..{:#x}",
+             kernel_stack.bottom(),
+             kernel_stack.top());
+    unsafe { switch_stack(&kernel_stack, kernel_main, &mut memory_controller) }
+}
 
-    // initialize our IDT
-    interrupts::init();
+/// Continues the initialization on the kernel stack. The boot stack is never used again, so the
+/// memory controller stays valid.
+#[cfg(not(test))]
+extern "C" fn kernel_main(memory_controller: &mut memory::MemoryController) -> ! {
+    // initialize our GDT, TSS and IDT
+    interrupts::init(memory_controller);
 
     // trigger a breakpoint exception
     unsafe { int!(3) };
 
+    // allocate physically contiguous frames, e.g. for DMA buffers
+    let block = memory::allocate_frames(4).expect("no block of 16 contiguous frames");
+    let run = memory::allocate_contiguous(3).expect("no run of 3 contiguous frames");
+    println!("contiguous frames: {:?} (16 frames), {:?} (3 frames)", block, run);
+    memory::deallocate_contiguous(run, 3);
+    memory::deallocate_frames(block, 4);
+
     println!("It did not crash!");
-    loop {}
+    memory::print_heap_stats();
+    hlt_loop()
 }
 
+/// Switches to the given stack and calls `f` with `memory_controller` on it.
+#[cfg(not(test))]
+unsafe fn switch_stack(stack: &memory::Stack,
+                       f: extern "C" fn(&mut memory::MemoryController) -> !,
+                       memory_controller: &mut memory::MemoryController)
+                       -> ! {
+    asm!("mov rsp, $0
+          call $1"
+         :
+         : "r"(stack.top().as_usize()), "r"(f), "{rdi}"(memory_controller)
+         :
+         : "intel", "volatile");
+    core::intrinsics::unreachable();
+}
+
+/// Halts the CPU until the next interrupt, forever. Used instead of spinning when there is
+/// nothing left to do.
+pub fn hlt_loop() -> ! {
+    loop {
+        unsafe { asm!("hlt" :::: "volatile") };
+    }
+}
+
+#[cfg(not(test))]
 fn enable_nxe_bit() {
     use x86::shared::msr::{IA32_EFER, rdmsr, wrmsr};
 
@@ -73,6 +130,7 @@ fn enable_nxe_bit() {
     }
 }
 
+#[cfg(not(test))]
 fn enable_write_protect_bit() {
     use x86::shared::control_regs::{cr0, cr0_write, CR0_WRITE_PROTECT};
 
@@ -89,11 +147,12 @@ extern "C" fn eh_personality() {}
 pub extern "C" fn panic_fmt(fmt: core::fmt::Arguments, file: &'static str, line: u32) -> ! {
     println!("\n\nPANIC in {} at line {}:", file, line);
     println!("    {}", fmt);
-    loop {}
+    hlt_loop()
 }
 
+#[cfg(not(test))]
 #[allow(non_snake_case)]
 #[no_mangle]
 pub extern "C" fn _Unwind_Resume() -> ! {
-    loop {}
+    hlt_loop()
 }
@@ -7,33 +7,48 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use memory::{Frame, FrameAllocator};
-use multiboot2::{MemoryAreaIter, MemoryArea};
+use memory::{Frame, FrameAllocator, BitmapFrameAllocator, MemoryArea, PhysAddr};
 
-/// A frame allocator that uses the memory areas from the multiboot information structure as
-/// source. The {kernel, multiboot}_{start, end} fields are used to avoid returning memory that is
-/// already in use.
+/// The maximum number of deallocated frames that are kept for reuse. The allocator is only used
+/// until the bitmap allocator takes over, so only a few frames are deallocated, e.g. page tables
+/// that `Mapper::unmap` freed.
+const FREE_FRAME_POOL_SIZE: usize = 128;
+
+/// A frame allocator that uses the given memory areas, usually from the multiboot information
+/// structure, as source. The {kernel, multiboot}_{start, end} fields are used to avoid returning
+/// memory that is already in use.
+///
+/// Deallocated frames are kept in a fixed-size pool, which is used before new frames are taken
+/// from the memory areas. The pool is not stored in the frames themselves, since they are not
+/// mapped anywhere after the kernel is remapped. Deallocating more than `FREE_FRAME_POOL_SIZE`
+/// frames panics.
 ///
 /// `kernel_end` and `multiboot_end` are _inclusive_ bounds.
-pub struct AreaFrameAllocator {
+pub struct AreaFrameAllocator<I> {
     next_free_frame: Frame,
-    current_area: Option<&'static MemoryArea>,
-    areas: MemoryAreaIter,
+    free_frames: [usize; FREE_FRAME_POOL_SIZE],
+    free_frame_count: usize,
+    current_area: Option<MemoryArea>,
+    areas: I,
     kernel_start: Frame,
     kernel_end: Frame,
     multiboot_start: Frame,
     multiboot_end: Frame,
 }
 
-impl AreaFrameAllocator {
-    pub fn new(kernel_start: usize,
-               kernel_end: usize,
-               multiboot_start: usize,
-               multiboot_end: usize,
-               memory_areas: MemoryAreaIter)
-               -> AreaFrameAllocator {
+impl<I> AreaFrameAllocator<I>
+    where I: Iterator<Item = MemoryArea> + Clone
+{
+    pub fn new(kernel_start: PhysAddr,
+               kernel_end: PhysAddr,
+               multiboot_start: PhysAddr,
+               multiboot_end: PhysAddr,
+               memory_areas: I)
+               -> AreaFrameAllocator<I> {
         let mut allocator = AreaFrameAllocator {
-            next_free_frame: Frame::containing_address(0),
+            next_free_frame: Frame { number: 0 },
+            free_frames: [0; FREE_FRAME_POOL_SIZE],
+            free_frame_count: 0,
             current_area: None,
             areas: memory_areas,
             kernel_start: Frame::containing_address(kernel_start),
@@ -45,17 +60,33 @@ impl AreaFrameAllocator {
         allocator
     }
 
+    /// Hands all remaining frames over to the given bitmap allocator. Frames that were allocated
+    /// through this allocator stay marked as used.
+    pub fn hand_over(self, allocator: &mut BitmapFrameAllocator) {
+        // areas are used in ascending order, so all frames below `next_free_frame` were either
+        // handed out or are in the pool
+        if self.next_free_frame.number > 0 {
+            let last_used_frame = Frame { number: self.next_free_frame.number - 1 };
+            for frame in Frame::range_inclusive(Frame { number: 0 }, last_used_frame) {
+                allocator.mark_used(frame);
+            }
+        }
+        for &number in &self.free_frames[..self.free_frame_count] {
+            allocator.deallocate_frame(Frame { number: number });
+        }
+    }
+
     fn choose_next_area(&mut self) {
         self.current_area = self.areas
             .clone()
             .filter(|area| {
                 let address = area.base_addr + area.length - 1;
-                Frame::containing_address(address as usize) >= self.next_free_frame
+                Frame::containing_address(PhysAddr::new(address as usize)) >= self.next_free_frame
             })
             .min_by_key(|area| area.base_addr);
 
         if let Some(area) = self.current_area {
-            let start_frame = Frame::containing_address(area.base_addr as usize);
+            let start_frame = Frame::containing_address(PhysAddr::new(area.base_addr as usize));
             if self.next_free_frame < start_frame {
                 self.next_free_frame = start_frame;
             }
@@ -63,8 +94,16 @@ impl AreaFrameAllocator {
     }
 }
 
-impl FrameAllocator for AreaFrameAllocator {
+impl<I> FrameAllocator for AreaFrameAllocator<I>
+    where I: Iterator<Item = MemoryArea> + Clone
+{
     fn allocate_frame(&mut self) -> Option<Frame> {
+        if self.free_frame_count > 0 {
+            // reuse a previously deallocated frame
+            self.free_frame_count -= 1;
+            return Some(Frame { number: self.free_frames[self.free_frame_count] });
+        }
+
         if let Some(area) = self.current_area {
             // "clone" the frame to return it if it's free. Frame doesn't
             // implement Clone, but we can construct an identical frame.
@@ -73,7 +112,7 @@ impl FrameAllocator for AreaFrameAllocator {
             // the last frame of the current area
             let current_area_last_frame = {
                 let address = area.base_addr + area.length - 1;
-                Frame::containing_address(address as usize)
+                Frame::containing_address(PhysAddr::new(address as usize))
             };
 
             if frame > current_area_last_frame {
@@ -97,7 +136,156 @@ impl FrameAllocator for AreaFrameAllocator {
         }
     }
 
-    fn deallocate_frame(&mut self, _frame: Frame) {
-        unimplemented!()
+    fn deallocate_frame(&mut self, frame: Frame) {
+        assert!(frame < self.next_free_frame,
+                "frame {:?} was never allocated",
+                frame);
+        assert!(!self.free_frames[..self.free_frame_count].contains(&frame.number),
+                "frame {:?} was deallocated twice",
+                frame);
+
+        if frame.number + 1 == self.next_free_frame.number {
+            // `frame` is the most recently allocated one, so we can just hand it out again
+            self.next_free_frame.number -= 1;
+        } else {
+            assert!(self.free_frame_count < FREE_FRAME_POOL_SIZE,
+                    "no space left for deallocated frame {:?}",
+                    frame);
+            self.free_frames[self.free_frame_count] = frame.number;
+            self.free_frame_count += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FREE_FRAME_POOL_SIZE;
+    use memory::{Frame, FrameAllocator, PAGE_SIZE};
+    use memory::test_util::{area, area_allocator as allocator, all_frames, bitmap,
+                            bitmap_allocator};
+    use std::vec::Vec;
+
+    #[test]
+    fn areas_are_used_in_ascending_order() {
+        // unsorted areas with a hole between 0x9f000 and 0x100000
+        let areas = [area(0x10_0000, 0x20_0000), area(0, 0x9_f000)];
+        let mut allocator =
+            allocator(&areas, (0x30_0000, 0x30_0fff), (0x31_0000, 0x31_0fff));
+
+        let frames = all_frames(&mut allocator);
+        assert_eq!(frames.len(), 0x9f + 0x100);
+        assert_eq!(frames[0], 0);
+        assert_eq!(frames[0x9e], 0x9e);
+        assert_eq!(frames[0x9f], 0x100);
+        assert!(frames.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn kernel_and_multiboot_are_skipped() {
+        let areas = [area(0x10_0000, 0x20_0000)];
+        let mut allocator =
+            allocator(&areas, (0x10_0000, 0x10_ffff), (0x11_0000, 0x11_0fff));
+
+        let frames = all_frames(&mut allocator);
+        assert_eq!(frames.len(), 0x100 - 0x11);
+        assert_eq!(frames[0], 0x111);
+        assert!(frames.iter().all(|&number| number * PAGE_SIZE >= 0x11_1000));
+    }
+
+    #[test]
+    fn deallocated_frames_are_reused() {
+        let areas = [area(0, 0x10_0000)];
+        let mut allocator =
+            allocator(&areas, (0x10_0000, 0x10_0fff), (0x10_1000, 0x10_1fff));
+
+        let first = allocator.allocate_frame().unwrap();
+        let second = allocator.allocate_frame().unwrap();
+        let third = allocator.allocate_frame().unwrap();
+        allocator.deallocate_frame(first);
+        allocator.deallocate_frame(third);
+
+        assert_eq!(allocator.allocate_frame(), Some(Frame { number: 0 }));
+        assert_eq!(allocator.allocate_frame(), Some(Frame { number: 2 }));
+        assert_eq!(allocator.allocate_frame(), Some(Frame { number: 3 }));
+        allocator.deallocate_frame(second);
+    }
+
+    #[test]
+    #[should_panic]
+    fn deallocate_unallocated_frame() {
+        let areas = [area(0, 0x10_0000)];
+        let mut allocator =
+            allocator(&areas, (0x10_0000, 0x10_0fff), (0x10_1000, 0x10_1fff));
+        allocator.deallocate_frame(Frame { number: 5 });
+    }
+
+    #[test]
+    fn full_pool_is_reused() {
+        let areas = [area(0, 0x10_0000)];
+        let mut allocator =
+            allocator(&areas, (0x10_0000, 0x10_0fff), (0x10_1000, 0x10_1fff));
+
+        let frames = all_frames(&mut allocator);
+        assert_eq!(frames.len(), 0x100);
+        // free every other frame, so that none of them is the most recently allocated one
+        let freed: Vec<usize> = frames.into_iter().filter(|&number| number % 2 == 0).collect();
+        assert_eq!(freed.len(), FREE_FRAME_POOL_SIZE);
+        for &number in &freed {
+            allocator.deallocate_frame(Frame { number: number });
+        }
+
+        let mut reused = all_frames(&mut allocator);
+        reused.sort();
+        assert_eq!(reused, freed);
+    }
+
+    #[test]
+    #[should_panic(expected = "no space left")]
+    fn pool_overflow() {
+        let areas = [area(0, 0x10_0000)];
+        let mut allocator =
+            allocator(&areas, (0x10_0000, 0x10_0fff), (0x10_1000, 0x10_1fff));
+
+        let frames = all_frames(&mut allocator);
+        for &number in frames.iter().filter(|&&number| number % 2 == 0) {
+            allocator.deallocate_frame(Frame { number: number });
+        }
+        allocator.deallocate_frame(Frame { number: 1 });
+    }
+
+    #[test]
+    #[should_panic]
+    fn deallocate_frame_twice() {
+        let areas = [area(0, 0x10_0000)];
+        let mut allocator =
+            allocator(&areas, (0x10_0000, 0x10_0fff), (0x10_1000, 0x10_1fff));
+
+        let first = allocator.allocate_frame().unwrap();
+        allocator.allocate_frame().unwrap();
+        allocator.deallocate_frame(first.clone());
+        allocator.deallocate_frame(first);
+    }
+
+    #[test]
+    fn hand_over_to_bitmap_allocator() {
+        let areas = [area(0, 0x10_0000)];
+        let mut allocator = allocator(&areas, (0x1_0000, 0x1_0fff), (0x1_1000, 0x1_1fff));
+        for _ in 0..0x20 {
+            allocator.allocate_frame().unwrap();
+        }
+        allocator.deallocate_frame(Frame { number: 3 });
+        allocator.deallocate_frame(Frame { number: 0x12 });
+
+        let mut bitmap = bitmap(&areas);
+        let mut bitmap_allocator =
+            bitmap_allocator(&mut bitmap, &areas, (0x1_0000, 0x1_0fff), (0x1_1000, 0x1_1fff));
+        allocator.hand_over(&mut bitmap_allocator);
+
+        // the frames in the pool are available again, the frames that are still allocated and
+        // the kernel and multiboot frames stay used
+        assert_eq!(bitmap_allocator.used_frames(), 0x22 - 2);
+        assert_eq!(bitmap_allocator.allocate_frame(), Some(Frame { number: 3 }));
+        assert_eq!(bitmap_allocator.allocate_frame(), Some(Frame { number: 0x12 }));
+        assert_eq!(bitmap_allocator.allocate_frame(), Some(Frame { number: 0x22 }));
     }
 }
@@ -0,0 +1,374 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use memory::{PAGE_SIZE, Frame, FrameAllocator, MemoryArea, PhysAddr};
+
+const BITS_PER_WORD: usize = 64;
+
+/// A frame allocator that stores one bit per physical frame, which is set if the frame is in use.
+///
+/// The bitmap covers all frames from address 0 up to the end of the highest memory area. Frames
+/// that don't lie completely inside a memory area are permanently marked as used, as are the
+/// frames of the kernel and the multiboot information structure. A second bitmap marks these
+/// frames as reserved, so that they are never deallocated by mistake.
+///
+/// The bitmaps are too large for the initial heap if there is a lot of physical memory, so the
+/// caller provides their memory. `bitmap_words` returns the required size.
+///
+/// `kernel_end` and `multiboot_end` are _inclusive_ bounds.
+pub struct BitmapFrameAllocator<'a> {
+    bitmap: &'a mut [u64],
+    // the bits of frames that can never be allocated, e.g. memory holes or the kernel
+    reserved: &'a mut [u64],
+    frame_count: usize,
+    usable_frames: usize,
+    free_frames: usize,
+    // all words before this index are completely used
+    next_word: usize,
+}
+
+impl<'a> BitmapFrameAllocator<'a> {
+    /// Returns the number of words that the bitmaps for the given memory areas need.
+    pub fn bitmap_words<I>(memory_areas: I) -> usize
+        where I: Iterator<Item = MemoryArea>
+    {
+        2 * word_count(frame_count(memory_areas))
+    }
+
+    /// Creates a new allocator that stores its bitmap in `bitmap`, which must be at least
+    /// `bitmap_words` words large. The previous content of `bitmap` is overwritten.
+    pub fn new<I>(bitmap: &'a mut [u64],
+                  kernel_start: PhysAddr,
+                  kernel_end: PhysAddr,
+                  multiboot_start: PhysAddr,
+                  multiboot_end: PhysAddr,
+                  memory_areas: I)
+                  -> BitmapFrameAllocator<'a>
+        where I: Iterator<Item = MemoryArea> + Clone
+    {
+        let frame_count = frame_count(memory_areas.clone());
+        let word_count = word_count(frame_count);
+        assert!(bitmap.len() >= 2 * word_count,
+                "bitmap has {} words, but {} are needed",
+                bitmap.len(),
+                2 * word_count);
+
+        // all frames are used and reserved until they are found in a memory area
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+        let (used, reserved) = bitmap[..2 * word_count].split_at_mut(word_count);
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap: used,
+            reserved: reserved,
+            frame_count: frame_count,
+            usable_frames: 0,
+            free_frames: 0,
+            next_word: 0,
+        };
+
+        for area in memory_areas {
+            // only use frames that lie completely inside the area
+            let start = (area.base_addr as usize + PAGE_SIZE - 1) / PAGE_SIZE;
+            let end = (area.base_addr + area.length) as usize / PAGE_SIZE;
+            for number in start..end {
+                allocator.set_used(number, false);
+                allocator.set_reserved(number, false);
+            }
+        }
+        allocator.usable_frames = allocator.free_frames;
+
+        let kernel_start = Frame::containing_address(kernel_start);
+        let kernel_end = Frame::containing_address(kernel_end);
+        for frame in Frame::range_inclusive(kernel_start, kernel_end) {
+            allocator.mark_reserved(frame);
+        }
+
+        let multiboot_start = Frame::containing_address(multiboot_start);
+        let multiboot_end = Frame::containing_address(multiboot_end);
+        for frame in Frame::range_inclusive(multiboot_start, multiboot_end) {
+            allocator.mark_reserved(frame);
+        }
+
+        allocator
+    }
+
+    /// Returns the number of frames that are currently available for allocation.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Returns the number of usable frames that are currently in use.
+    pub fn used_frames(&self) -> usize {
+        self.usable_frames - self.free_frames
+    }
+
+    /// Marks the given frame as used without allocating it, e.g. because it was handed out by
+    /// another allocator. Frames outside of the memory areas are ignored.
+    pub fn mark_used(&mut self, frame: Frame) {
+        if frame.number < self.frame_count {
+            self.set_used(frame.number, true);
+        }
+    }
+
+    /// Marks the given frame as used for good, so that it can't be deallocated.
+    fn mark_reserved(&mut self, frame: Frame) {
+        if frame.number < self.frame_count {
+            self.set_used(frame.number, true);
+            self.set_reserved(frame.number, true);
+        }
+    }
+
+    /// Marks the `count` frames starting at `frame` as used if all of them are free, e.g. to
+    /// hand them over to another allocator. Returns whether the frames were free.
+    pub fn reserve_frames(&mut self, frame: Frame, count: usize) -> bool {
+        let range = frame.number..(frame.number + count);
+        if range.end > self.frame_count || range.clone().any(|number| self.is_used(number)) {
+            return false;
+        }
+        for number in range {
+            self.set_used(number, true);
+        }
+        true
+    }
+
+    /// Allocates `count` physically contiguous frames and returns the first one.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<Frame> {
+        assert!(count > 0, "cannot allocate zero frames");
+
+        let mut run_start = self.next_word * BITS_PER_WORD;
+        for number in run_start..self.frame_count {
+            if self.is_used(number) {
+                run_start = number + 1;
+            } else if number + 1 - run_start == count {
+                for number in run_start..(run_start + count) {
+                    self.set_used(number, true);
+                }
+                return Some(Frame { number: run_start });
+            }
+        }
+        None
+    }
+
+    /// Deallocates `count` contiguous frames starting at `frame`.
+    pub fn deallocate_contiguous(&mut self, frame: Frame, count: usize) {
+        for number in frame.number..(frame.number + count) {
+            self.deallocate_frame(Frame { number: number });
+        }
+    }
+
+    fn is_used(&self, number: usize) -> bool {
+        self.bitmap[number / BITS_PER_WORD] & (1 << (number % BITS_PER_WORD)) != 0
+    }
+
+    fn is_reserved(&self, number: usize) -> bool {
+        self.reserved[number / BITS_PER_WORD] & (1 << (number % BITS_PER_WORD)) != 0
+    }
+
+    fn set_reserved(&mut self, number: usize, reserved: bool) {
+        let index = number / BITS_PER_WORD;
+        let mask: u64 = 1 << (number % BITS_PER_WORD);
+        if reserved {
+            self.reserved[index] |= mask;
+        } else {
+            self.reserved[index] &= !mask;
+        }
+    }
+
+    fn set_used(&mut self, number: usize, used: bool) {
+        if self.is_used(number) == used {
+            return;
+        }
+
+        let index = number / BITS_PER_WORD;
+        let mask: u64 = 1 << (number % BITS_PER_WORD);
+        if used {
+            self.bitmap[index] |= mask;
+            self.free_frames -= 1;
+        } else {
+            self.bitmap[index] &= !mask;
+            self.free_frames += 1;
+            if index < self.next_word {
+                self.next_word = index;
+            }
+        }
+    }
+}
+
+/// Returns the number of frames from address 0 up to the end of the highest memory area.
+fn frame_count<I>(memory_areas: I) -> usize
+    where I: Iterator<Item = MemoryArea>
+{
+    memory_areas.map(|area| (area.base_addr + area.length) as usize / PAGE_SIZE)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Returns the number of words of a bitmap with one bit for each of `frame_count` frames.
+fn word_count(frame_count: usize) -> usize {
+    (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD
+}
+
+impl<'a> FrameAllocator for BitmapFrameAllocator<'a> {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        while self.next_word < self.bitmap.len() {
+            let word = self.bitmap[self.next_word];
+            if word != !0 {
+                let number = self.next_word * BITS_PER_WORD + (!word).trailing_zeros() as usize;
+                self.set_used(number, true);
+                return Some(Frame { number: number });
+            }
+            self.next_word += 1;
+        }
+        None // no free frames left
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        assert!(frame.number < self.frame_count && !self.is_reserved(frame.number),
+                "frame {:?} is never allocated",
+                frame);
+        assert!(self.is_used(frame.number),
+                "frame {:?} is not allocated",
+                frame);
+        self.set_used(frame.number, false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BitmapFrameAllocator;
+    use memory::{Frame, FrameAllocator};
+    use memory::test_util::{area, all_frames, bitmap, bitmap_allocator as allocator};
+
+    #[test]
+    fn bitmap_size() {
+        assert_eq!(BitmapFrameAllocator::bitmap_words([].iter().cloned()), 0);
+        // one word for the used frames and one for the reserved frames
+        assert_eq!(BitmapFrameAllocator::bitmap_words([area(0, 0x4_0000)].iter().cloned()), 2);
+        assert_eq!(BitmapFrameAllocator::bitmap_words([area(0, 0x4_1000)].iter().cloned()), 4);
+        // the size depends on the end of the highest area, not on the amount of memory
+        let areas = [area(0x1_0000_0000, 0x1_0000_1000), area(0, 0x1000)];
+        assert_eq!(BitmapFrameAllocator::bitmap_words(areas.iter().cloned()),
+                   2 * (0x10_0000 / 64 + 1));
+    }
+
+    #[test]
+    #[should_panic]
+    fn bitmap_too_small() {
+        let areas = [area(0, 0x10_0000)];
+        let mut bitmap = vec![0; 3];
+        allocator(&mut bitmap, &areas, (0x10_0000, 0x10_0fff), (0x10_1000, 0x10_1fff));
+    }
+
+    #[test]
+    fn frame_counts() {
+        // the first area doesn't end and the second doesn't start at a frame boundary
+        let areas = [area(0, 0x9_f800), area(0x10_0800, 0x20_0000)];
+        let mut bitmap = bitmap(&areas);
+        let mut allocator =
+            allocator(&mut bitmap, &areas, (0x30_0000, 0x30_0fff), (0x31_0000, 0x31_0fff));
+
+        assert_eq!(allocator.free_frames(), 0x9f + 0xff);
+        assert_eq!(allocator.used_frames(), 0);
+
+        let frame = allocator.allocate_frame().unwrap();
+        assert_eq!(allocator.free_frames(), 0x9f + 0xff - 1);
+        assert_eq!(allocator.used_frames(), 1);
+        allocator.deallocate_frame(frame);
+        assert_eq!(allocator.used_frames(), 0);
+
+        let frames = all_frames(&mut allocator);
+        assert_eq!(frames.len(), 0x9f + 0xff);
+        assert_eq!(frames[0x9e], 0x9e);
+        assert_eq!(frames[0x9f], 0x101);
+        assert_eq!(allocator.free_frames(), 0);
+        assert_eq!(allocator.used_frames(), 0x9f + 0xff);
+    }
+
+    #[test]
+    fn kernel_and_multiboot_are_reserved() {
+        let areas = [area(0, 0x10_0000)];
+        let mut bitmap = bitmap(&areas);
+        let mut allocator =
+            allocator(&mut bitmap, &areas, (0x1_0000, 0x1_ffff), (0x8_0800, 0x8_17ff));
+
+        assert_eq!(allocator.used_frames(), 0x10 + 2);
+        let frames = all_frames(&mut allocator);
+        assert_eq!(frames.len(), 0x100 - 0x10 - 2);
+        assert!(frames.iter().all(|&number| number < 0x10 || number >= 0x20));
+        assert!(frames.iter().all(|&number| number != 0x80 && number != 0x81));
+    }
+
+    #[test]
+    fn contiguous_frames() {
+        let areas = [area(0, 0x10_0000)];
+        let mut bitmap = bitmap(&areas);
+        let mut allocator =
+            allocator(&mut bitmap, &areas, (0x3_0000, 0x3_0fff), (0x3_1000, 0x3_1fff));
+
+        // the kernel and multiboot frames split the area into runs of 0x30 and 0xce frames
+        assert_eq!(allocator.allocate_contiguous(0x20), Some(Frame { number: 0 }));
+        assert_eq!(allocator.allocate_contiguous(0x20), Some(Frame { number: 0x32 }));
+        assert_eq!(allocator.allocate_contiguous(0x10), Some(Frame { number: 0x20 }));
+        assert_eq!(allocator.allocate_contiguous(0xaf), None);
+        assert_eq!(allocator.allocate_contiguous(0xae), Some(Frame { number: 0x52 }));
+        assert_eq!(allocator.free_frames(), 0);
+
+        allocator.deallocate_contiguous(Frame { number: 0x40 }, 0x10);
+        assert_eq!(allocator.free_frames(), 0x10);
+        assert_eq!(allocator.allocate_contiguous(0x11), None);
+        assert_eq!(allocator.allocate_contiguous(0x10), Some(Frame { number: 0x40 }));
+    }
+
+    #[test]
+    fn reserve_frames() {
+        let areas = [area(0, 0x10_0000)];
+        let mut bitmap = bitmap(&areas);
+        let mut allocator =
+            allocator(&mut bitmap, &areas, (0x3_0000, 0x3_0fff), (0x3_1000, 0x3_1fff));
+
+        assert!(allocator.reserve_frames(Frame { number: 0x10 }, 0x20));
+        assert!(!allocator.reserve_frames(Frame { number: 0x2f }, 2));
+        assert!(!allocator.reserve_frames(Frame { number: 0xff }, 2));
+        assert_eq!(allocator.used_frames(), 0x20 + 2);
+        assert_eq!(allocator.allocate_contiguous(0x10), Some(Frame { number: 0 }));
+        assert_eq!(allocator.allocate_frame(), Some(Frame { number: 0x32 }));
+    }
+
+    #[test]
+    #[should_panic(expected = "is never allocated")]
+    fn deallocate_memory_hole() {
+        let areas = [area(0, 0x9_f000), area(0x10_0000, 0x20_0000)];
+        let mut bitmap = bitmap(&areas);
+        let mut allocator =
+            allocator(&mut bitmap, &areas, (0x10_0000, 0x10_0fff), (0x10_1000, 0x10_1fff));
+        allocator.deallocate_frame(Frame { number: 0xa0 });
+    }
+
+    #[test]
+    #[should_panic(expected = "is never allocated")]
+    fn deallocate_kernel_frame() {
+        let areas = [area(0, 0x10_0000)];
+        let mut bitmap = bitmap(&areas);
+        let mut allocator =
+            allocator(&mut bitmap, &areas, (0x1_0000, 0x1_ffff), (0x8_0000, 0x8_0fff));
+        allocator.deallocate_frame(Frame { number: 0x10 });
+    }
+
+    #[test]
+    #[should_panic(expected = "is not allocated")]
+    fn deallocate_free_frame() {
+        let areas = [area(0, 0x10_0000)];
+        let mut bitmap = bitmap(&areas);
+        let mut allocator =
+            allocator(&mut bitmap, &areas, (0x10_0000, 0x10_0fff), (0x10_1000, 0x10_1fff));
+        allocator.deallocate_frame(Frame { number: 5 });
+    }
+}
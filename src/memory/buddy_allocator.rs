@@ -0,0 +1,313 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use memory::{PAGE_SIZE, Frame, FrameAllocator, MemoryArea};
+use collections::Vec;
+use collections::btree_set::BTreeSet;
+use core::cmp;
+
+/// The largest supported block order. A block of order `n` consists of `2^n` frames.
+pub const MAX_ORDER: usize = 10;
+
+/// A buddy allocator for physically contiguous blocks of frames.
+///
+/// Every block of order `n` starts at a frame number that is a multiple of `2^n`. Freed blocks
+/// are merged with their buddy block whenever the buddy is free, too.
+pub struct BuddyAllocator {
+    // the start frame numbers of the free blocks, indexed by order
+    free_lists: Vec<BTreeSet<usize>>,
+    free_frames: usize,
+}
+
+impl BuddyAllocator {
+    pub fn new() -> BuddyAllocator {
+        BuddyAllocator {
+            free_lists: (0..(MAX_ORDER + 1)).map(|_| BTreeSet::new()).collect(),
+            free_frames: 0,
+        }
+    }
+
+    /// Adds frames of the given memory areas to the allocator until `limit` frames were added
+    /// and returns the number of added frames.
+    ///
+    /// Each area is split into the largest aligned blocks that fit. `reserve` is called for each
+    /// block and returns whether the frames of the block were free and are now owned by this
+    /// allocator. Otherwise, the block is split into its two halves, which are tried separately.
+    pub fn add_areas<I, F>(&mut self, memory_areas: I, limit: usize, mut reserve: F) -> usize
+        where I: Iterator<Item = MemoryArea>,
+              F: FnMut(Frame, usize) -> bool
+    {
+        let mut added = 0;
+        for area in memory_areas {
+            // only use frames that lie completely inside the area
+            let mut number = (area.base_addr as usize + PAGE_SIZE - 1) / PAGE_SIZE;
+            let end = (area.base_addr + area.length) as usize / PAGE_SIZE;
+            while number < end && added < limit {
+                // use the largest block that is aligned and fits into the range and the limit
+                let mut order = MAX_ORDER;
+                while number % (1 << order) != 0 || number + (1 << order) > end ||
+                      added + (1 << order) > limit {
+                    order -= 1;
+                }
+                added += self.add_block(number, order, &mut reserve);
+                number += 1 << order;
+            }
+        }
+        added
+    }
+
+    /// Adds the block of order `order` at frame `number` or, if `reserve` rejects it, the parts
+    /// of it that `reserve` accepts. Returns the number of added frames.
+    fn add_block<F>(&mut self, number: usize, order: usize, reserve: &mut F) -> usize
+        where F: FnMut(Frame, usize) -> bool
+    {
+        if reserve(Frame { number: number }, order) {
+            self.deallocate_frames(Frame { number: number }, order);
+            1 << order
+        } else if order > 0 {
+            self.add_block(number, order - 1, reserve) +
+            self.add_block(number + (1 << (order - 1)), order - 1, reserve)
+        } else {
+            0
+        }
+    }
+
+    /// Returns the number of frames that are currently available for allocation.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Allocates a block of `2^order` contiguous frames that is aligned to `2^order` frames and
+    /// returns its first frame.
+    pub fn allocate_frames(&mut self, order: usize) -> Option<Frame> {
+        assert!(order <= MAX_ORDER, "order {} is too large", order);
+
+        let mut current_order = order;
+        while self.free_lists[current_order].is_empty() {
+            current_order += 1;
+            if current_order > MAX_ORDER {
+                return None; // no block is large enough
+            }
+        }
+
+        let number = *self.free_lists[current_order].iter().next().unwrap();
+        self.free_lists[current_order].remove(&number);
+
+        // split the block and free the upper halves until it has the requested size
+        while current_order > order {
+            current_order -= 1;
+            self.free_lists[current_order].insert(number + (1 << current_order));
+        }
+
+        self.free_frames -= 1 << order;
+        Some(Frame { number: number })
+    }
+
+    /// Deallocates the block of `2^order` frames starting at `frame` and merges it with its
+    /// buddies.
+    pub fn deallocate_frames(&mut self, frame: Frame, order: usize) {
+        assert!(order <= MAX_ORDER, "order {} is too large", order);
+        assert!(frame.number % (1 << order) == 0,
+                "frame {:?} is not aligned to order {}",
+                frame,
+                order);
+        // the buddy of a block that is freed twice is often free too, in which case the block
+        // would be merged before it is inserted, so this has to be checked first
+        assert!(!self.is_free(frame.number, order),
+                "block at frame {} was freed twice",
+                frame.number);
+
+        self.free_frames += 1 << order;
+
+        let mut number = frame.number;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = number ^ (1 << order);
+            if !self.free_lists[order].remove(&buddy) {
+                break; // buddy is in use
+            }
+            number = cmp::min(number, buddy);
+            order += 1;
+        }
+
+        self.free_lists[order].insert(number);
+    }
+
+    /// Returns whether the block of order `order` at frame `number` or a larger block that
+    /// contains it is free.
+    fn is_free(&self, number: usize, order: usize) -> bool {
+        (order..(MAX_ORDER + 1)).any(|order| {
+            let block = number & !((1 << order) - 1);
+            self.free_lists[order].contains(&block)
+        })
+    }
+}
+
+impl FrameAllocator for BuddyAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        self.allocate_frames(0)
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        self.deallocate_frames(frame, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BuddyAllocator, MAX_ORDER};
+    use memory::{Frame, FrameAllocator, MemoryArea};
+    use memory::test_util::frame_area as area;
+    use std::vec::Vec;
+
+    /// Returns the start frame numbers of the free blocks, indexed by order.
+    fn free_blocks(allocator: &BuddyAllocator) -> Vec<Vec<usize>> {
+        allocator.free_lists.iter().map(|list| list.iter().cloned().collect()).collect()
+    }
+
+    fn allocator(areas: &[MemoryArea]) -> BuddyAllocator {
+        let mut allocator = BuddyAllocator::new();
+        allocator.add_areas(areas.iter().cloned(), !0, |_, _| true);
+        allocator
+    }
+
+    #[test]
+    fn blocks_are_aligned() {
+        // 0x3 + 0x4 + 0x8 + 0x400 + 0x400 + 0x10 + 0x1 frames
+        let allocator = allocator(&[area(0x3fd, 0xc11)]);
+        let blocks = free_blocks(&allocator);
+        assert_eq!(blocks[0], vec![0x3fd, 0xc10]);
+        assert_eq!(blocks[1], vec![0x3fe]);
+        assert_eq!(blocks[4], vec![0xc00]);
+        assert_eq!(blocks[MAX_ORDER], vec![0x400, 0x800]);
+        assert_eq!(allocator.free_frames(), 0xc11 - 0x3fd);
+    }
+
+    #[test]
+    fn partial_frames_are_ignored() {
+        let mut allocator = BuddyAllocator::new();
+        let area = MemoryArea {
+            base_addr: 0x800,
+            length: 0x2000,
+        };
+        allocator.add_areas([area].iter().cloned(), !0, |_, _| true);
+        assert_eq!(free_blocks(&allocator)[0], vec![1]);
+        assert_eq!(allocator.free_frames(), 1);
+    }
+
+    #[test]
+    fn areas_with_holes() {
+        // the holes of the memory map and the frames that `reserve` rejects are both skipped
+        let areas = [area(0, 0x9f), area(0x100, 0x200)];
+        let mut allocator = BuddyAllocator::new();
+        let added = allocator.add_areas(areas.iter().cloned(), !0, |frame, order| {
+            frame.number + (1 << order) <= 0x110 || frame.number > 0x110
+        });
+        assert_eq!(added, 0x9f + 0xff);
+        assert_eq!(allocator.free_frames(), 0x9f + 0xff);
+
+        let blocks = free_blocks(&allocator);
+        assert_eq!(blocks[0], vec![0x9e, 0x111]);
+        assert_eq!(blocks[3], vec![0x90, 0x118]);
+        assert_eq!(blocks[4], vec![0x80, 0x100]);
+        assert_eq!(blocks[7], vec![0, 0x180]);
+        assert!(blocks.iter().flat_map(|list| list.iter()).all(|&number| number != 0x110));
+    }
+
+    #[test]
+    fn limit() {
+        let mut allocator = BuddyAllocator::new();
+        let added = allocator.add_areas([area(0, 0x1000)].iter().cloned(), 0x600, |_, _| true);
+        assert_eq!(added, 0x600);
+        let blocks = free_blocks(&allocator);
+        assert_eq!(blocks[MAX_ORDER], vec![0]);
+        assert_eq!(blocks[MAX_ORDER - 1], vec![0x400]);
+    }
+
+    #[test]
+    fn split() {
+        let mut allocator = allocator(&[area(0, 0x400)]);
+        assert_eq!(allocator.allocate_frames(0), Some(Frame { number: 0 }));
+        assert_eq!(allocator.allocate_frames(2), Some(Frame { number: 4 }));
+        assert_eq!(allocator.allocate_frames(0), Some(Frame { number: 1 }));
+        assert_eq!(allocator.free_frames(), 0x400 - 6);
+
+        let blocks = free_blocks(&allocator);
+        assert_eq!(blocks[1], vec![2]);
+        assert_eq!(blocks[3], vec![8]);
+        assert_eq!(blocks[MAX_ORDER - 1], vec![0x200]);
+        assert!(blocks[MAX_ORDER].is_empty());
+    }
+
+    #[test]
+    fn coalesce_on_free() {
+        let mut allocator = allocator(&[area(0, 0x400)]);
+        let frames: Vec<Frame> = (0..4).map(|_| allocator.allocate_frames(0).unwrap()).collect();
+
+        // frame 1 and 2 are no buddies, so they can't be merged
+        let mut frames = frames.into_iter();
+        let (first, second, third, fourth) = (frames.next().unwrap(),
+                                              frames.next().unwrap(),
+                                              frames.next().unwrap(),
+                                              frames.next().unwrap());
+        allocator.deallocate_frames(second, 0);
+        allocator.deallocate_frames(third, 0);
+        assert_eq!(free_blocks(&allocator)[0], vec![1, 2]);
+
+        allocator.deallocate_frames(first, 0);
+        assert_eq!(free_blocks(&allocator)[0], vec![2]);
+        assert_eq!(free_blocks(&allocator)[1], vec![0]);
+
+        allocator.deallocate_frames(fourth, 0);
+        let blocks = free_blocks(&allocator);
+        assert!(blocks[..MAX_ORDER].iter().all(|list| list.is_empty()));
+        assert_eq!(blocks[MAX_ORDER], vec![0]);
+        assert_eq!(allocator.free_frames(), 0x400);
+    }
+
+    #[test]
+    fn exhaustion() {
+        let mut allocator = allocator(&[area(0x400, 0x600)]);
+        assert_eq!(allocator.allocate_frames(MAX_ORDER), None);
+        assert_eq!(allocator.allocate_frames(MAX_ORDER - 1), Some(Frame { number: 0x400 }));
+        assert_eq!(allocator.allocate_frames(0), None);
+        assert_eq!(allocator.allocate_frame(), None);
+        assert_eq!(allocator.free_frames(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn unaligned_free() {
+        let mut allocator = allocator(&[area(0, 0x400)]);
+        allocator.allocate_frames(MAX_ORDER);
+        allocator.deallocate_frames(Frame { number: 2 }, 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_free() {
+        let mut allocator = allocator(&[area(0, 0x400)]);
+        let first = allocator.allocate_frames(0).unwrap();
+        allocator.allocate_frames(0).unwrap();
+        allocator.deallocate_frames(first.clone(), 0);
+        allocator.deallocate_frames(first, 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_free_of_merged_block() {
+        let mut allocator = allocator(&[area(0, 0x400)]);
+        let first = allocator.allocate_frames(0).unwrap();
+        let second = allocator.allocate_frames(0).unwrap();
+        allocator.deallocate_frames(second, 0);
+        allocator.deallocate_frames(first.clone(), 0);
+        // the block was merged with its buddy, so it is not in the free list of order 0 anymore
+        allocator.deallocate_frames(first, 0);
+    }
+}
@@ -8,29 +8,124 @@
 // except according to those terms.
 
 pub use self::area_frame_allocator::AreaFrameAllocator;
-pub use self::paging::remap_the_kernel;
-use self::paging::PhysicalAddress;
+pub use self::bitmap_frame_allocator::BitmapFrameAllocator;
+pub use self::buddy_allocator::BuddyAllocator;
+pub use self::paging::{PhysAddr, VirtAddr};
+#[cfg(not(test))]
+use self::paging::Mapper;
+#[cfg(not(test))]
+pub use self::stack_allocator::Stack;
+#[cfg(not(test))]
 use multiboot2::BootInformation;
+#[cfg(not(test))]
+use spin::Mutex;
+#[cfg(not(test))]
+use core::{mem, slice};
+#[cfg(not(test))]
+use bump_allocator::BumpArena;
+#[cfg(not(test))]
+use hole_list_allocator;
 
 mod area_frame_allocator;
+mod bitmap_frame_allocator;
+mod buddy_allocator;
 mod paging;
+#[cfg(not(test))]
+mod stack_allocator;
+// fixtures that are shared by the tests of the frame allocators
+#[cfg(test)]
+mod test_util;
 
 pub const PAGE_SIZE: usize = 4096;
 
-pub fn init(boot_info: &BootInformation) {
+/// The kernel is linked at this offset above its physical load address. Until the kernel is
+/// remapped, the boot code maps the whole first GiB of physical memory at this offset.
+pub const KERNEL_OFFSET: usize = 0xffffffff_80000000;
+
+/// Returns the physical address of the given kernel address. The boot code is linked at its
+/// physical address, so addresses below `KERNEL_OFFSET` are returned unchanged.
+fn kernel_to_physical(address: usize) -> PhysAddr {
+    if address >= KERNEL_OFFSET {
+        PhysAddr::new(address - KERNEL_OFFSET)
+    } else {
+        PhysAddr::new(address)
+    }
+}
+
+/// The active page table after `init`.
+///
+/// The heap maps new pages through this table, so the heap must not be used while it or the
+/// frame allocator is locked. To avoid deadlocks, it must always be locked before
+/// `FRAME_ALLOCATOR`.
+#[cfg(not(test))]
+static ACTIVE_TABLE: Mutex<Option<paging::KernelPageTable>> = Mutex::new(None);
+
+/// The frame allocator that is used after the heap is initialized.
+#[cfg(not(test))]
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator<'static>>> = Mutex::new(None);
+
+/// The virtual address of the bitmap of `FRAME_ALLOCATOR`. It has its own P4 entry.
+#[cfg(not(test))]
+const BITMAP_START: usize = 0xffff_c100_0000_0000;
+
+/// The maximum number of frames that are reserved for physically contiguous allocations (4 MiB).
+///
+/// The buddy allocator keeps its free blocks in a `Vec` of `BTreeSet`s on the heap, so it can
+/// only be filled after the initial heap is mapped.
+#[cfg(not(test))]
+const CONTIGUOUS_FRAME_COUNT: usize = 1024;
+
+/// The allocator for physically contiguous blocks of frames, e.g. for DMA buffers.
+#[cfg(not(test))]
+static CONTIGUOUS_ALLOCATOR: Mutex<Option<BuddyAllocator>> = Mutex::new(None);
+
+/// The virtual memory region in which stacks are allocated. It has its own P4 entry.
+#[cfg(not(test))]
+const STACK_AREA_START: usize = 0xffff_c080_0000_0000;
+#[cfg(not(test))]
+const STACK_AREA_SIZE: usize = 1024 * 1024 * 1024; // 1 GiB
+
+/// The memory of the arena that `init` uses before the heap exists (4 KiB).
+#[cfg(not(test))]
+static mut EARLY_BOOT_MEMORY: [u64; 512] = [0; 512];
+
+/// The size of the kernel stack in pages (64 KiB).
+#[cfg(not(test))]
+pub const KERNEL_STACK_PAGES: usize = 16;
+
+#[cfg(not(test))]
+pub fn init(boot_info: &BootInformation) -> MemoryController {
     assert_has_not_been_called!("memory::init must be called only once");
 
     let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
+
+    // the memory map is used several times, so it is converted once and stored in an arena
+    let early_arena = unsafe {
+        BumpArena::new(EARLY_BOOT_MEMORY.as_ptr() as usize,
+                       mem::size_of_val(&EARLY_BOOT_MEMORY))
+    };
+    let memory_areas = {
+        let empty_area = MemoryArea {
+            base_addr: 0,
+            length: 0,
+        };
+        let areas = early_arena.alloc_slice(memory_map_tag.memory_areas().count(), empty_area)
+            .expect("too many memory areas");
+        for (area, multiboot_area) in areas.iter_mut().zip(memory_map_tag.memory_areas()) {
+            *area = MemoryArea::from(multiboot_area);
+        }
+        &*areas
+    };
     let elf_sections_tag = boot_info.elf_sections_tag().expect("Elf sections tag required");
 
     let kernel_start = elf_sections_tag.sections()
         .filter(|s| s.is_allocated())
-        .map(|s| s.addr)
+        .map(|s| kernel_to_physical(s.addr as usize))
         .min()
         .unwrap();
     let kernel_end = elf_sections_tag.sections()
         .filter(|s| s.is_allocated())
-        .map(|s| s.addr + s.size)
+        .map(|s| kernel_to_physical((s.addr + s.size) as usize))
         .max()
         .unwrap();
 
@@ -41,22 +136,241 @@ pub fn init(boot_info: &BootInformation) {
              boot_info.start_address(),
              boot_info.end_address());
 
-    let mut frame_allocator = AreaFrameAllocator::new(kernel_start as usize,
-                                                      kernel_end as usize,
-                                                      boot_info.start_address(),
-                                                      boot_info.end_address(),
-                                                      memory_map_tag.memory_areas());
+    let multiboot_start = kernel_to_physical(boot_info.start_address());
+    let multiboot_end = kernel_to_physical(boot_info.end_address());
+
+    // the area frame allocator is only used until the bitmap allocator takes over, so its small
+    // pool for deallocated frames suffices for the page tables that are freed until then
+    let mut frame_allocator = AreaFrameAllocator::new(kernel_start,
+                                                      kernel_end,
+                                                      multiboot_start,
+                                                      multiboot_end,
+                                                      memory_areas.iter().cloned());
 
     let mut active_table = paging::remap_the_kernel(&mut frame_allocator, boot_info);
 
     use self::paging::Page;
-    use hole_list_allocator::{HEAP_START, HEAP_SIZE};
+    use hole_list_allocator::{HEAP_START, HEAP_INITIAL_SIZE};
+
+    // the bitmap can be much larger than the initial heap, so it is placed in its own pages,
+    // which are the last frames that the area frame allocator hands out
+    let bitmap = {
+        let word_count = BitmapFrameAllocator::bitmap_words(memory_areas.iter().cloned());
+        assert!(word_count > 0, "the memory map contains no memory");
+        let start_page = Page::containing_address(VirtAddr::new(BITMAP_START));
+        let end_page = Page::containing_address(VirtAddr::new(BITMAP_START + word_count * 8 - 1));
+
+        let flags = paging::WRITABLE | paging::NO_EXECUTE;
+        let mut flush_batch = paging::FlushBatch::new();
+        for page in Page::range_inclusive(start_page, end_page) {
+            let flush = active_table.map(page, flags, &mut frame_allocator)
+                .expect("failed to map frame bitmap page");
+            flush_batch.add(flush);
+        }
+        flush_batch.flush();
 
-    let heap_start_page = Page::containing_address(HEAP_START);
-    let heap_end_page = Page::containing_address(HEAP_START + HEAP_SIZE - 1);
+        unsafe { slice::from_raw_parts_mut(BITMAP_START as *mut u64, word_count) }
+    };
 
+    let mut bitmap_allocator = BitmapFrameAllocator::new(bitmap,
+                                                         kernel_start,
+                                                         kernel_end,
+                                                         multiboot_start,
+                                                         multiboot_end,
+                                                         memory_areas.iter().cloned());
+    frame_allocator.hand_over(&mut bitmap_allocator);
+
+    // the heap maps further pages on demand through `map_heap_pages` later
+    let heap_start_page = Page::containing_address(VirtAddr::new(HEAP_START));
+    let heap_end_page =
+        Page::containing_address(VirtAddr::new(HEAP_START + HEAP_INITIAL_SIZE - 1));
+
+    let mut flush_batch = paging::FlushBatch::new();
     for page in Page::range_inclusive(heap_start_page, heap_end_page) {
-        active_table.map(page, paging::WRITABLE, &mut frame_allocator);
+        let flush = active_table.map(page, paging::WRITABLE, &mut bitmap_allocator)
+            .expect("failed to map heap page");
+        flush_batch.add(flush);
+    }
+    flush_batch.flush();
+
+    // move free frames to the buddy allocator, in blocks that are as large as possible
+    let mut buddy_allocator = BuddyAllocator::new();
+    buddy_allocator.add_areas(memory_areas.iter().cloned(),
+                              CONTIGUOUS_FRAME_COUNT,
+                              |frame, order| bitmap_allocator.reserve_frames(frame, 1 << order));
+
+    println!("frames used: {}, frames free: {}, contiguous frames free: {}",
+             bitmap_allocator.used_frames(),
+             bitmap_allocator.free_frames(),
+             buddy_allocator.free_frames());
+
+    dump_page_table(&active_table);
+
+    *ACTIVE_TABLE.lock() = Some(active_table);
+    *FRAME_ALLOCATOR.lock() = Some(bitmap_allocator);
+    *CONTIGUOUS_ALLOCATOR.lock() = Some(buddy_allocator);
+    hole_list_allocator::set_map_pages_fn(map_heap_pages);
+
+    let stack_allocator = {
+        let stack_start_page = Page::containing_address(VirtAddr::new(STACK_AREA_START));
+        let stack_end_page =
+            Page::containing_address(VirtAddr::new(STACK_AREA_START + STACK_AREA_SIZE - 1));
+        let stack_pages = Page::range_inclusive(stack_start_page, stack_end_page);
+        stack_allocator::StackAllocator::new(stack_pages)
+    };
+
+    MemoryController { stack_allocator: stack_allocator }
+}
+
+/// Prints the mappings of the kernel page table with the `page-table-dump` feature.
+#[cfg(all(feature = "page-table-dump", not(test)))]
+fn dump_page_table(active_table: &paging::KernelPageTable) {
+    println!("kernel page table:");
+    active_table.dump();
+}
+
+#[cfg(all(not(feature = "page-table-dump"), not(test)))]
+fn dump_page_table(_active_table: &paging::KernelPageTable) {}
+
+/// Maps the heap pages in `start..(start + size)` and returns the number of bytes that were
+/// mapped. The heap allocator calls this function when it needs to grow.
+#[cfg(not(test))]
+fn map_heap_pages(start: usize, size: usize) -> usize {
+    use self::paging::Page;
+
+    let mut active_table = ACTIVE_TABLE.lock();
+    let active_table = active_table.as_mut().expect("memory::init must be called first");
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().expect("memory::init must be called first");
+
+    let start_page = Page::containing_address(VirtAddr::new(start));
+    let end_page = Page::containing_address(VirtAddr::new(start + size - 1));
+    let mut mapped = 0;
+    for page in Page::range_inclusive(start_page, end_page) {
+        match active_table.map(page, paging::WRITABLE, frame_allocator) {
+            Ok(flush) => flush.flush(),
+            Err(paging::MapToError::FrameAllocationFailed) => break, // out of physical memory
+            Err(err) => panic!("failed to map heap page {:?}: {:?}", page, err),
+        }
+        mapped += PAGE_SIZE;
+    }
+    mapped
+}
+
+/// Prints the usage counters of the heap and its slab allocator. With the `heap-leak-tracking`
+/// feature, all outstanding allocations are listed, too.
+#[cfg(not(test))]
+pub fn print_heap_stats() {
+    let stats = hole_list_allocator::stats();
+    println!("heap: {} bytes allocated (peak {}), {} bytes mapped",
+             stats.bytes_allocated,
+             stats.peak_bytes_allocated,
+             stats.heap_size);
+    println!("heap: {} allocations, {} deallocations, {} failed",
+             stats.allocations,
+             stats.deallocations,
+             stats.failed_allocations);
+
+    for class in hole_list_allocator::slab_stats().iter().filter(|class| class.slabs > 0) {
+        println!("slab {:>4}: {} slabs, {} allocated, {} free, {} allocations total",
+                 class.object_size,
+                 class.slabs,
+                 class.allocated_objects,
+                 class.free_objects,
+                 class.total_allocations);
+    }
+
+    print_outstanding_allocations();
+}
+
+#[cfg(all(feature = "heap-leak-tracking", not(test)))]
+fn print_outstanding_allocations() {
+    let untracked = hole_list_allocator::for_each_outstanding_allocation(|allocation| {
+        println!("allocation at {:#x}: {} bytes, allocated at {:#x}",
+                 allocation.address,
+                 allocation.size,
+                 allocation.call_site);
+    });
+    if untracked > 0 {
+        println!("{} further allocations were not recorded", untracked);
+    }
+}
+
+#[cfg(all(not(feature = "heap-leak-tracking"), not(test)))]
+fn print_outstanding_allocations() {}
+
+/// Allocates kernel stacks after `init`.
+#[cfg(not(test))]
+pub struct MemoryController {
+    stack_allocator: stack_allocator::StackAllocator,
+}
+
+#[cfg(not(test))]
+impl MemoryController {
+    /// Allocates a stack of `size_in_pages` pages with a guard page below it.
+    pub fn alloc_stack(&mut self, size_in_pages: usize) -> Option<Stack> {
+        let mut active_table = ACTIVE_TABLE.lock();
+        let active_table = active_table.as_mut().expect("memory::init must be called first");
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_mut().expect("memory::init must be called first");
+        self.stack_allocator.alloc_stack(active_table, frame_allocator, size_in_pages)
+    }
+}
+
+/// Allocates `2^order` physically contiguous frames that are aligned to `2^order` frames.
+///
+/// Returns the first frame of the block or `None` if no such block is available.
+#[cfg(not(test))]
+pub fn allocate_frames(order: usize) -> Option<Frame> {
+    CONTIGUOUS_ALLOCATOR.lock()
+        .as_mut()
+        .expect("memory::init must be called first")
+        .allocate_frames(order)
+}
+
+/// Deallocates a block of frames that was allocated through `allocate_frames`.
+#[cfg(not(test))]
+pub fn deallocate_frames(frame: Frame, order: usize) {
+    CONTIGUOUS_ALLOCATOR.lock()
+        .as_mut()
+        .expect("memory::init must be called first")
+        .deallocate_frames(frame, order)
+}
+
+/// Allocates `count` physically contiguous frames from the general frame allocator. Unlike
+/// `allocate_frames`, the count doesn't need to be a power of two, but the frames are not aligned.
+///
+/// Returns the first frame or `None` if no such run of free frames exists.
+#[cfg(not(test))]
+pub fn allocate_contiguous(count: usize) -> Option<Frame> {
+    FRAME_ALLOCATOR.lock()
+        .as_mut()
+        .expect("memory::init must be called first")
+        .allocate_contiguous(count)
+}
+
+/// Deallocates frames that were allocated through `allocate_contiguous`.
+#[cfg(not(test))]
+pub fn deallocate_contiguous(frame: Frame, count: usize) {
+    FRAME_ALLOCATOR.lock()
+        .as_mut()
+        .expect("memory::init must be called first")
+        .deallocate_contiguous(frame, count)
+}
+
+/// A region of usable physical memory, e.g. an available area of the multiboot memory map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryArea {
+    pub base_addr: u64,
+    pub length: u64,
+}
+
+impl<'a> From<&'a ::multiboot2::MemoryArea> for MemoryArea {
+    fn from(area: &'a ::multiboot2::MemoryArea) -> MemoryArea {
+        MemoryArea {
+            base_addr: area.base_addr,
+            length: area.length,
+        }
     }
 }
 
@@ -66,12 +380,12 @@ pub struct Frame {
 }
 
 impl Frame {
-    fn containing_address(address: usize) -> Frame {
-        Frame { number: address / PAGE_SIZE }
+    fn containing_address(address: PhysAddr) -> Frame {
+        Frame { number: address.as_usize() / PAGE_SIZE }
     }
 
-    fn start_address(&self) -> PhysicalAddress {
-        self.number * PAGE_SIZE
+    fn start_address(&self) -> PhysAddr {
+        PhysAddr::new(self.number * PAGE_SIZE)
     }
 
     fn clone(&self) -> Frame {
@@ -109,3 +423,35 @@ pub trait FrameAllocator {
     fn allocate_frame(&mut self) -> Option<Frame>;
     fn deallocate_frame(&mut self, frame: Frame);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{kernel_to_physical, Frame, PhysAddr, KERNEL_OFFSET, PAGE_SIZE};
+    use std::vec::Vec;
+
+    #[test]
+    fn frame_containing_address() {
+        assert_eq!(Frame::containing_address(PhysAddr::new(0)), Frame { number: 0 });
+        assert_eq!(Frame::containing_address(PhysAddr::new(PAGE_SIZE - 1)),
+                   Frame { number: 0 });
+        assert_eq!(Frame::containing_address(PhysAddr::new(0x12_3456)),
+                   Frame { number: 0x123 });
+        assert_eq!(Frame { number: 0x123 }.start_address(), PhysAddr::new(0x12_3000));
+    }
+
+    #[test]
+    fn frame_range() {
+        let frames: Vec<Frame> = Frame::range_inclusive(Frame { number: 3 }, Frame { number: 5 })
+            .collect();
+        assert_eq!(frames,
+                   vec![Frame { number: 3 }, Frame { number: 4 }, Frame { number: 5 }]);
+        assert_eq!(Frame::range_inclusive(Frame { number: 5 }, Frame { number: 3 }).count(), 0);
+    }
+
+    #[test]
+    fn kernel_addresses() {
+        assert_eq!(kernel_to_physical(KERNEL_OFFSET + 0x10_0000), PhysAddr::new(0x10_0000));
+        // the boot code is linked at its physical address
+        assert_eq!(kernel_to_physical(0x10_0000), PhysAddr::new(0x10_0000));
+    }
+}
@@ -0,0 +1,280 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+
+/// The highest physical address that can be mapped through a page table entry.
+const MAX_PHYSICAL_ADDRESS: usize = 0x000fffff_ffffffff;
+
+/// A physical memory address.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PhysAddr(usize);
+
+/// A canonical virtual memory address.
+///
+/// Only the lower 48 bits of a virtual address are used for translation. The remaining bits must
+/// be copies of bit 47, otherwise the address is invalid.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VirtAddr(usize);
+
+impl PhysAddr {
+    pub fn new(address: usize) -> PhysAddr {
+        PhysAddr::try_new(address).expect("invalid physical address")
+    }
+
+    pub fn try_new(address: usize) -> Option<PhysAddr> {
+        if address <= MAX_PHYSICAL_ADDRESS {
+            Some(PhysAddr(address))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_usize(&self) -> usize {
+        self.0
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0 as u64
+    }
+
+    pub fn align_down(&self, align: usize) -> PhysAddr {
+        PhysAddr(align_down(self.0, align))
+    }
+
+    pub fn align_up(&self, align: usize) -> PhysAddr {
+        PhysAddr::new(align_up(self.0, align))
+    }
+
+    pub fn is_aligned(&self, align: usize) -> bool {
+        self.align_down(align) == *self
+    }
+}
+
+impl VirtAddr {
+    pub fn new(address: usize) -> VirtAddr {
+        match VirtAddr::try_new(address) {
+            Some(address) => address,
+            None => panic!("invalid address: 0x{:x}", address),
+        }
+    }
+
+    pub fn try_new(address: usize) -> Option<VirtAddr> {
+        if address < 0x0000_8000_0000_0000 || address >= 0xffff_8000_0000_0000 {
+            Some(VirtAddr(address))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_usize(&self) -> usize {
+        self.0
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0 as u64
+    }
+
+    /// Aligns the address downwards.
+    ///
+    /// Panics if the result is not canonical. This happens for alignments of 2^48 and above,
+    /// which clear bit 47 of a higher half address without clearing the bits above it.
+    pub fn align_down(&self, align: usize) -> VirtAddr {
+        VirtAddr::new(align_down(self.0, align))
+    }
+
+    /// Aligns the address upwards.
+    ///
+    /// Panics if the result is not canonical, i.e. if the next aligned address lies in the
+    /// non-canonical hole or beyond the end of the address space. For example, aligning
+    /// `0x0000_7fff_ffff_f001` up to a page boundary panics.
+    pub fn align_up(&self, align: usize) -> VirtAddr {
+        VirtAddr::new(align_up(self.0, align))
+    }
+
+    pub fn is_aligned(&self, align: usize) -> bool {
+        self.align_down(align) == *self
+    }
+}
+
+/// Align downwards. Returns the greatest x with alignment `align`
+/// so that x <= addr. The alignment must be a power of 2.
+fn align_down(addr: usize, align: usize) -> usize {
+    assert!(align.is_power_of_two(), "`align` must be a power of 2");
+    addr & !(align - 1)
+}
+
+/// Align upwards. Returns the smallest x with alignment `align`
+/// so that x >= addr. The alignment must be a power of 2.
+fn align_up(addr: usize, align: usize) -> usize {
+    let aligned = addr.checked_add(align - 1).expect("aligned address overflows");
+    align_down(aligned, align)
+}
+
+impl Add<usize> for PhysAddr {
+    type Output = PhysAddr;
+
+    fn add(self, rhs: usize) -> PhysAddr {
+        PhysAddr::new(self.0 + rhs)
+    }
+}
+
+impl AddAssign<usize> for PhysAddr {
+    fn add_assign(&mut self, rhs: usize) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<usize> for PhysAddr {
+    type Output = PhysAddr;
+
+    fn sub(self, rhs: usize) -> PhysAddr {
+        PhysAddr::new(self.0 - rhs)
+    }
+}
+
+impl SubAssign<usize> for PhysAddr {
+    fn sub_assign(&mut self, rhs: usize) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub<PhysAddr> for PhysAddr {
+    type Output = usize;
+
+    fn sub(self, rhs: PhysAddr) -> usize {
+        self.0 - rhs.0
+    }
+}
+
+impl Add<usize> for VirtAddr {
+    type Output = VirtAddr;
+
+    fn add(self, rhs: usize) -> VirtAddr {
+        VirtAddr::new(self.0 + rhs)
+    }
+}
+
+impl AddAssign<usize> for VirtAddr {
+    fn add_assign(&mut self, rhs: usize) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<usize> for VirtAddr {
+    type Output = VirtAddr;
+
+    fn sub(self, rhs: usize) -> VirtAddr {
+        VirtAddr::new(self.0 - rhs)
+    }
+}
+
+impl SubAssign<usize> for VirtAddr {
+    fn sub_assign(&mut self, rhs: usize) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub<VirtAddr> for VirtAddr {
+    type Output = usize;
+
+    fn sub(self, rhs: VirtAddr) -> usize {
+        self.0 - rhs.0
+    }
+}
+
+impl fmt::Debug for PhysAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PhysAddr({:#x})", self.0)
+    }
+}
+
+impl fmt::Debug for VirtAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VirtAddr({:#x})", self.0)
+    }
+}
+
+impl fmt::LowerHex for PhysAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+impl fmt::LowerHex for VirtAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{align_down, align_up, PhysAddr, VirtAddr};
+
+    #[test]
+    fn canonical_addresses() {
+        assert!(VirtAddr::try_new(0x0000_7fff_ffff_ffff).is_some());
+        assert!(VirtAddr::try_new(0x0000_8000_0000_0000).is_none());
+        assert!(VirtAddr::try_new(0xffff_7fff_ffff_ffff).is_none());
+        assert!(VirtAddr::try_new(0xffff_8000_0000_0000).is_some());
+    }
+
+    #[test]
+    fn integer_conversions() {
+        let address = VirtAddr::new(0xffff_8000_dead_beef);
+        assert_eq!(address.as_usize(), 0xffff_8000_dead_beef);
+        assert_eq!(address.as_u64(), 0xffff_8000_dead_beef);
+        assert_eq!(PhysAddr::new(0xb8000).as_u64(), 0xb8000);
+    }
+
+    #[test]
+    fn physical_address_limit() {
+        assert!(PhysAddr::try_new(0x000f_ffff_ffff_ffff).is_some());
+        assert!(PhysAddr::try_new(0x0010_0000_0000_0000).is_none());
+    }
+
+    #[test]
+    fn align_helpers() {
+        assert_eq!(align_down(0x1234, 0x1000), 0x1000);
+        assert_eq!(align_down(0x1000, 0x1000), 0x1000);
+        assert_eq!(align_up(0x1234, 0x1000), 0x2000);
+        assert_eq!(align_up(0x1000, 0x1000), 0x1000);
+        assert_eq!(align_up(0, 8), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn align_non_power_of_two() {
+        align_up(0x1234, 0x1001);
+    }
+
+    #[test]
+    fn address_alignment() {
+        let address = VirtAddr::new(0xffff_8000_0000_1234);
+        assert_eq!(address.align_down(0x1000).as_usize(), 0xffff_8000_0000_1000);
+        assert_eq!(address.align_up(0x1000).as_usize(), 0xffff_8000_0000_2000);
+        assert!(!address.is_aligned(0x1000));
+        assert!(PhysAddr::new(0x20_0000).is_aligned(0x20_0000));
+        assert_eq!(PhysAddr::new(0x20_0001).align_up(0x20_0000), PhysAddr::new(0x40_0000));
+    }
+
+    #[test]
+    #[should_panic]
+    fn non_canonical_align_down() {
+        // the result would have bit 47 cleared, but the upper bits set
+        VirtAddr::new(0xffff_8000_0000_1234).align_down(0x1_0000_0000_0000);
+    }
+
+    #[test]
+    #[should_panic]
+    fn align_up_into_non_canonical_hole() {
+        VirtAddr::new(0x0000_7fff_ffff_f001).align_up(0x1000);
+    }
+}
@@ -8,7 +8,10 @@
 // except according to those terms.
 
 use memory::Frame;
+use memory::paging::PhysAddr;
+#[cfg(not(test))]
 use multiboot2::ElfSection;
+use multiboot2::ElfSectionFlags;
 
 pub struct Entry(u64);
 
@@ -27,15 +30,20 @@ impl Entry {
 
     pub fn pointed_frame(&self) -> Option<Frame> {
         if self.flags().contains(PRESENT) {
-            Some(Frame::containing_address(self.0 as usize & 0x000fffff_fffff000))
+            Some(Frame::containing_address(PhysAddr::new(self.0 as usize & 0x000fffff_fffff000)))
         } else {
             None
         }
     }
 
+    /// Replaces the flags of the entry and keeps the frame address.
+    pub fn set_flags(&mut self, flags: EntryFlags) {
+        self.0 = (self.0 & 0x000fffff_fffff000) | flags.bits();
+    }
+
     pub fn set(&mut self, frame: Frame, flags: EntryFlags) {
-        assert!(frame.start_address() & !0x000fffff_fffff000 == 0);
-        self.0 = (frame.start_address() as u64) | flags.bits();
+        assert!(frame.start_address().as_usize() & !0x000fffff_fffff000 == 0);
+        self.0 = frame.start_address().as_u64() | flags.bits();
     }
 }
 
@@ -55,22 +63,64 @@ bitflags! {
 }
 
 impl EntryFlags {
+    #[cfg(not(test))]
     pub fn from_elf_section_flags(section: &ElfSection) -> EntryFlags {
+        EntryFlags::from_elf_flags(section.flags())
+    }
+
+    fn from_elf_flags(elf_flags: ElfSectionFlags) -> EntryFlags {
         use multiboot2::{ELF_SECTION_ALLOCATED, ELF_SECTION_WRITABLE, ELF_SECTION_EXECUTABLE};
 
         let mut flags = EntryFlags::empty();
 
-        if section.flags().contains(ELF_SECTION_ALLOCATED) {
+        if elf_flags.contains(ELF_SECTION_ALLOCATED) {
             // section is loaded to memory
             flags = flags | PRESENT;
         }
-        if section.flags().contains(ELF_SECTION_WRITABLE) {
+        if elf_flags.contains(ELF_SECTION_WRITABLE) {
             flags = flags | WRITABLE;
         }
-        if !section.flags().contains(ELF_SECTION_EXECUTABLE) {
+        if !elf_flags.contains(ELF_SECTION_EXECUTABLE) {
             flags = flags | NO_EXECUTE;
         }
 
         flags
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::{Frame, PhysAddr};
+    use multiboot2::{ElfSectionFlags, ELF_SECTION_ALLOCATED, ELF_SECTION_WRITABLE,
+                     ELF_SECTION_EXECUTABLE};
+
+    #[test]
+    fn elf_flags() {
+        assert_eq!(EntryFlags::from_elf_flags(ElfSectionFlags::empty()), NO_EXECUTE);
+        assert_eq!(EntryFlags::from_elf_flags(ELF_SECTION_ALLOCATED),
+                   PRESENT | NO_EXECUTE);
+        assert_eq!(EntryFlags::from_elf_flags(ELF_SECTION_ALLOCATED | ELF_SECTION_WRITABLE),
+                   PRESENT | WRITABLE | NO_EXECUTE);
+        assert_eq!(EntryFlags::from_elf_flags(ELF_SECTION_ALLOCATED | ELF_SECTION_EXECUTABLE),
+                   PRESENT);
+    }
+
+    #[test]
+    fn entry_frame_and_flags() {
+        let frame = Frame::containing_address(PhysAddr::new(0x000f_ffff_ffff_f000));
+        let mut entry = Entry(0);
+        assert!(entry.is_unused());
+        assert_eq!(entry.pointed_frame(), None);
+
+        entry.set(Frame::containing_address(PhysAddr::new(0x000f_ffff_ffff_f000)),
+                  PRESENT | WRITABLE | NO_EXECUTE);
+        assert_eq!(entry.pointed_frame(), Some(frame));
+        assert_eq!(entry.flags(), PRESENT | WRITABLE | NO_EXECUTE);
+
+        entry.set_flags(PRESENT);
+        assert_eq!(entry.flags(), PRESENT);
+        assert_eq!(entry.pointed_frame().unwrap().start_address().as_usize(),
+                   0x000f_ffff_ffff_f000);
+    }
+}
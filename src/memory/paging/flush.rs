@@ -0,0 +1,98 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use super::Page;
+
+/// The maximum number of pages that a `FlushBatch` flushes one by one. If more pages were
+/// changed, the whole TLB is flushed instead.
+const MAX_SINGLE_FLUSHES: usize = 16;
+
+/// A pending TLB flush for a page whose mapping was changed.
+///
+/// The flush must either be performed or explicitly ignored, e.g. because the changed table is
+/// not the active one or because the whole TLB is flushed afterwards anyway.
+#[must_use = "the page must be flushed from the TLB or the flush must be ignored explicitly"]
+pub struct MapperFlush(Page);
+
+impl MapperFlush {
+    pub fn new(page: Page) -> MapperFlush {
+        MapperFlush(page)
+    }
+
+    /// Flushes the page from the TLB.
+    pub fn flush(self) {
+        unsafe { tlb::flush(self.0.start_address().as_usize()) };
+    }
+
+    /// Drops the flush without performing it.
+    pub fn ignore(self) {}
+}
+
+/// Collects the pending flushes of multiple pages and performs them at once.
+///
+/// Up to `MAX_SINGLE_FLUSHES` pages are flushed individually using `invlpg`. For larger batches
+/// it's cheaper to flush the whole TLB, see `tlb::flush_all` for its limits.
+#[must_use = "the batch must be flushed or ignored explicitly"]
+pub struct FlushBatch {
+    pages: [Page; MAX_SINGLE_FLUSHES],
+    count: usize,
+}
+
+impl FlushBatch {
+    pub fn new() -> FlushBatch {
+        FlushBatch {
+            pages: [Page { number: 0 }; MAX_SINGLE_FLUSHES],
+            count: 0,
+        }
+    }
+
+    /// Adds the pending flush to the batch.
+    pub fn add(&mut self, flush: MapperFlush) {
+        if self.count < MAX_SINGLE_FLUSHES {
+            self.pages[self.count] = flush.0;
+        }
+        self.count += 1;
+    }
+
+    /// Performs all pending flushes of the batch.
+    pub fn flush(self) {
+        if self.count > MAX_SINGLE_FLUSHES {
+            unsafe { tlb::flush_all() };
+        } else {
+            for page in &self.pages[..self.count] {
+                unsafe { tlb::flush(page.start_address().as_usize()) };
+            }
+        }
+    }
+
+    /// Drops all pending flushes without performing them.
+    pub fn ignore(self) {}
+}
+
+/// The TLB instructions are privileged, so the host tests replace them with no-ops.
+#[cfg(not(test))]
+pub mod tlb {
+    pub use x86::shared::tlb::flush;
+
+    /// Flushes the whole TLB by reloading CR3.
+    ///
+    /// Reloading CR3 doesn't evict entries of pages that are mapped with the `GLOBAL` flag. The
+    /// kernel doesn't enable global pages (CR4.PGE), so the flag is ignored and this flushes all
+    /// entries. If global pages are ever enabled, this function must toggle CR4.PGE instead.
+    pub unsafe fn flush_all() {
+        ::x86::shared::tlb::flush_all();
+    }
+}
+
+#[cfg(test)]
+pub mod tlb {
+    pub unsafe fn flush(_address: usize) {}
+
+    pub unsafe fn flush_all() {}
+}
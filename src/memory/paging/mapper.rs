@@ -7,21 +7,199 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use super::{VirtualAddress, PhysicalAddress, Page, ENTRY_COUNT};
+use super::{VirtAddr, PhysAddr, Page, ENTRY_COUNT};
 use super::entry::*;
-use super::table::{self, Table, Level4};
+use super::flush::{MapperFlush, FlushBatch};
+use super::table::{self, Table, Level4, TableAccess, RecursiveAccess, OffsetAccess};
+#[cfg(any(test, feature = "page-table-dump"))]
+use super::walker::MappingIter;
 use memory::{PAGE_SIZE, Frame, FrameAllocator};
 use core::ptr::Unique;
+use spin::Once;
 
-pub struct Mapper {
+/// Maps pages to frames in a page table hierarchy.
+///
+/// Both `RecursivePageTable` and `OffsetPageTable` implement this trait, so code that only maps
+/// and unmaps pages works with either way of accessing the page tables.
+pub trait Mapper {
+    /// Returns the frame that `page` is mapped to.
+    fn translate_page(&self, page: Page) -> Option<Frame>;
+
+    /// Returns the physical address that `virtual_address` is mapped to.
+    fn translate(&self, virtual_address: VirtAddr) -> Option<PhysAddr> {
+        let offset = virtual_address.as_usize() % PAGE_SIZE;
+        self.translate_page(Page::containing_address(virtual_address))
+            .map(|frame| frame.start_address() + offset)
+    }
+
+    /// Maps `page` to `frame`. Missing page tables are allocated from `allocator`. If the
+    /// mapping fails, the tables that were allocated for it are freed again.
+    fn map_to<A>(&mut self,
+                 page: Page,
+                 frame: Frame,
+                 flags: EntryFlags,
+                 allocator: &mut A)
+                 -> Result<MapperFlush, MapToError>
+        where A: FrameAllocator;
+
+    /// Maps `page` to a frame from `allocator`.
+    fn map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A)
+              -> Result<MapperFlush, MapToError>
+        where A: FrameAllocator
+    {
+        let frame = allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        match self.map_to(page, frame.clone(), flags, allocator) {
+            Ok(flush) => Ok(flush),
+            Err(err) => {
+                allocator.deallocate_frame(frame);
+                Err(err)
+            }
+        }
+    }
+
+    /// Maps the page with the same address as `frame` to `frame`.
+    fn identity_map<A>(&mut self,
+                       frame: Frame,
+                       flags: EntryFlags,
+                       allocator: &mut A)
+                       -> Result<MapperFlush, MapToError>
+        where A: FrameAllocator
+    {
+        let page = Page::containing_address(VirtAddr::new(frame.start_address().as_usize()));
+        self.map_to(page, frame, flags, allocator)
+    }
+
+    /// Maps the 2MiB region starting at `page` to the 2MiB region starting at `frame` using a
+    /// single huge page. Both `page` and `frame` must be 2MiB aligned, otherwise `NotAligned`
+    /// is returned.
+    fn map_to_2mib<A>(&mut self,
+                      page: Page,
+                      frame: Frame,
+                      flags: EntryFlags,
+                      allocator: &mut A)
+                      -> Result<MapperFlush, MapToError>
+        where A: FrameAllocator;
+
+    /// Maps the 1GiB region starting at `page` to the 1GiB region starting at `frame` using a
+    /// single huge page. Both `page` and `frame` must be 1GiB aligned and the CPU must support
+    /// 1GiB pages (see `supports_1gib_pages`), otherwise `NotAligned` or `HugePageNotSupported`
+    /// is returned.
+    fn map_to_1gib<A>(&mut self,
+                      page: Page,
+                      frame: Frame,
+                      flags: EntryFlags,
+                      allocator: &mut A)
+                      -> Result<MapperFlush, MapToError>
+        where A: FrameAllocator;
+
+    /// Unmaps the given page and returns the frame it was mapped to. The frame itself is not
+    /// deallocated, but P1, P2 and P3 tables that become empty are freed.
+    ///
+    /// The page is not flushed from the TLB. This is the responsibility of the caller, who gets
+    /// a `MapperFlush` for it.
+    ///
+    /// If `page` is the start of a 2MiB or 1GiB huge page, the whole huge page is unmapped and
+    /// its first frame is returned.
+    fn unmap<A>(&mut self,
+                page: Page,
+                allocator: &mut A)
+                -> Result<(Frame, MapperFlush), UnmapError>
+        where A: FrameAllocator;
+
+    /// Replaces the flags of the given mapped page. The page keeps pointing to the same frame.
+    ///
+    /// If `page` is the start of a 2MiB or 1GiB huge page, the flags of the whole huge page are
+    /// updated.
+    fn update_flags(&mut self,
+                    page: Page,
+                    flags: EntryFlags)
+                    -> Result<MapperFlush, FlagUpdateError>;
+
+    /// Replaces the flags of all mapped pages from `start` to `end` (inclusive). Huge pages in
+    /// the range are updated as a whole.
+    ///
+    /// The whole range is checked first. If a page can't be updated, the error is returned and
+    /// none of the pages are changed.
+    fn update_flags_range(&mut self,
+                          start: Page,
+                          end: Page,
+                          flags: EntryFlags)
+                          -> Result<FlushBatch, FlagUpdateError>;
+}
+
+/// An error that can occur when mapping a page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapToError {
+    /// The page is already mapped.
+    AlreadyMapped,
+    /// A frame for the page or for a page table could not be allocated.
+    FrameAllocationFailed,
+    /// The page lies in a region that is already mapped by a huge page.
+    ParentEntryHugePage,
+    /// The page or the frame is not aligned to the size of the huge page.
+    NotAligned,
+    /// The CPU does not support huge pages of the requested size.
+    HugePageNotSupported,
+}
+
+/// An error that can occur when unmapping a page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnmapError {
+    /// The page is not mapped.
+    PageNotMapped,
+    /// The page is part of a huge page, but not its start.
+    ParentEntryHugePage,
+}
+
+/// An error that can occur when updating the flags of a mapped page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlagUpdateError {
+    /// The page is not mapped.
+    PageNotMapped,
+    /// The page is part of a huge page, but not its start.
+    ParentEntryHugePage,
+}
+
+/// A page table hierarchy. The `TableAccess` determines how its tables are reached in memory.
+pub struct MappedPageTable<T: TableAccess> {
     p4: Unique<Table<Level4>>,
+    access: T,
 }
 
-impl Mapper {
-    pub unsafe fn new() -> Mapper {
-        Mapper { p4: Unique::new(table::P4) }
+/// The active page table, accessed through its recursive P4 entry.
+#[cfg(not(test))]
+pub type RecursivePageTable = MappedPageTable<RecursiveAccess>;
+
+/// A page table that is accessed through the physical memory window.
+pub type OffsetPageTable = MappedPageTable<OffsetAccess>;
+
+#[cfg(not(test))]
+impl RecursivePageTable {
+    /// Creates a mapper for the active page table, which must be recursively mapped.
+    pub unsafe fn new() -> RecursivePageTable {
+        MappedPageTable {
+            p4: Unique::new(table::P4),
+            access: RecursiveAccess,
+        }
+    }
+}
+
+impl OffsetPageTable {
+    /// Creates a mapper for the page table hierarchy with the P4 table in `p4_frame`.
+    ///
+    /// All physical memory must be mapped at `physical_memory_offset`.
+    pub unsafe fn from_p4_frame(p4_frame: Frame, physical_memory_offset: usize) -> OffsetPageTable {
+        let access = OffsetAccess::new(physical_memory_offset);
+        MappedPageTable {
+            p4: Unique::new(access.table_pointer(p4_frame)),
+            access: access,
+        }
     }
+}
 
+impl<T> MappedPageTable<T>
+    where T: TableAccess
+{
     pub fn p4(&self) -> &Table<Level4> {
         unsafe { self.p4.get() }
     }
@@ -30,14 +208,190 @@ impl Mapper {
         unsafe { self.p4.get_mut() }
     }
 
-    pub fn translate(&self, virtual_address: VirtualAddress) -> Option<PhysicalAddress> {
-        let offset = virtual_address % PAGE_SIZE;
-        self.translate_page(Page::containing_address(virtual_address))
-            .map(|frame| frame.number * PAGE_SIZE + offset)
+    /// Returns an iterator over all present mappings, see `MappingIter`.
+    #[cfg(any(test, feature = "page-table-dump"))]
+    pub fn mappings(&self) -> MappingIter<T> {
+        MappingIter::new(self.p4(), self.access)
+    }
+
+    /// Prints all present mappings to the screen.
+    ///
+    /// To dump an `InactivePageTable`, call this method on the mapper that
+    /// `ActivePageTable::with` passes to its closure or on `InactivePageTable::mapper`.
+    #[cfg(feature = "page-table-dump")]
+    pub fn dump(&self) {
+        for mapping in self.mappings() {
+            println!("{:#x}-{:#x} -> {:#x}-{:#x} {:?}",
+                     mapping.start,
+                     mapping.start.as_usize() + mapping.size - 1,
+                     mapping.frame_start,
+                     mapping.frame_start.as_usize() + mapping.size - 1,
+                     mapping.flags);
+        }
+    }
+
+    /// Unmaps `page` like `Mapper::unmap`, but keeps the page tables even if they become empty.
+    ///
+    /// This is meant for pages that are mapped again and again, like the temporary page, whose
+    /// tables come from a small reserve that can't take back arbitrary frames.
+    pub fn unmap_keep_tables(&mut self, page: Page) -> Result<(Frame, MapperFlush), UnmapError> {
+        self.unmap_entry(page).map(|frame| (frame, MapperFlush::new(page)))
+    }
+
+    /// Creates the first `levels` of the P3, P2 and P1 tables for `page` if they don't exist. If
+    /// one of them can't be created, the tables that this call created are freed again.
+    fn create_tables<A>(&mut self,
+                        page: Page,
+                        levels: usize,
+                        allocator: &mut A)
+                        -> Result<(), MapToError>
+        where A: FrameAllocator
+    {
+        let existing = self.existing_tables(page);
+        let result = self.create_tables_inner(page, levels, allocator);
+        if result.is_err() {
+            self.free_empty_tables(page, existing, allocator);
+        }
+        result
+    }
+
+    fn create_tables_inner<A>(&mut self,
+                              page: Page,
+                              levels: usize,
+                              allocator: &mut A)
+                              -> Result<(), MapToError>
+        where A: FrameAllocator
+    {
+        let access = self.access;
+        let p3 = self.p4_mut().next_table_create(page.p4_index(), allocator, &access)?;
+        if levels > 1 {
+            let p2 = p3.next_table_create(page.p3_index(), allocator, &access)?;
+            if levels > 2 {
+                p2.next_table_create(page.p2_index(), allocator, &access)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns how many of the P3, P2 and P1 tables for `page` exist. A table can only exist if
+    /// its parent exists, so these are always the upper ones.
+    fn existing_tables(&self, page: Page) -> usize {
+        let access = self.access;
+        let p3 = self.p4().next_table(page.p4_index(), &access);
+        let p2 = p3.and_then(|p3| p3.next_table(page.p3_index(), &access));
+        let p1 = p2.and_then(|p2| p2.next_table(page.p2_index(), &access));
+        [p3.is_some(), p2.is_some(), p1.is_some()].iter().filter(|&&exists| exists).count()
+    }
+
+    /// Frees the P1, P2 and P3 tables for `page` bottom-up as long as they are empty. The upper
+    /// `keep` of them are never freed.
+    fn free_empty_tables<A>(&mut self, page: Page, keep: usize, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        let access = self.access;
+        let p4 = self.p4_mut();
+        if let Some(p3) = p4.next_table_mut(page.p4_index(), &access) {
+            if let Some(p2) = p3.next_table_mut(page.p3_index(), &access) {
+                if keep < 3 {
+                    p2.free_next_table_if_empty(page.p2_index(), allocator, &access);
+                }
+            }
+            if keep < 2 {
+                p3.free_next_table_if_empty(page.p3_index(), allocator, &access);
+            }
+        }
+        if keep < 1 {
+            p4.free_next_table_if_empty(page.p4_index(), allocator, &access);
+        }
     }
 
-    pub fn translate_page(&self, page: Page) -> Option<Frame> {
-        let p3 = self.p4().next_table(page.p4_index());
+    /// Clears the entry that maps `page` and returns the frame it pointed to.
+    fn unmap_entry(&mut self, page: Page) -> Result<Frame, UnmapError> {
+        let access = self.access;
+        let p3 = self.p4_mut()
+            .next_table_mut(page.p4_index(), &access)
+            .ok_or(UnmapError::PageNotMapped)?;
+        if p3[page.p3_index()].flags().contains(HUGE_PAGE) {
+            if page.number % (ENTRY_COUNT * ENTRY_COUNT) != 0 {
+                return Err(UnmapError::ParentEntryHugePage);
+            }
+            let frame = p3[page.p3_index()].pointed_frame().ok_or(UnmapError::PageNotMapped)?;
+            p3[page.p3_index()].set_unused();
+            Ok(frame)
+        } else {
+            let p2 = p3.next_table_mut(page.p3_index(), &access)
+                .ok_or(UnmapError::PageNotMapped)?;
+            if p2[page.p2_index()].flags().contains(HUGE_PAGE) {
+                if page.number % ENTRY_COUNT != 0 {
+                    return Err(UnmapError::ParentEntryHugePage);
+                }
+                let frame = p2[page.p2_index()]
+                    .pointed_frame()
+                    .ok_or(UnmapError::PageNotMapped)?;
+                p2[page.p2_index()].set_unused();
+                Ok(frame)
+            } else {
+                let p1 = p2.next_table_mut(page.p2_index(), &access)
+                    .ok_or(UnmapError::PageNotMapped)?;
+                let frame = p1[page.p1_index()]
+                    .pointed_frame()
+                    .ok_or(UnmapError::PageNotMapped)?;
+                p1[page.p1_index()].set_unused();
+                Ok(frame)
+            }
+        }
+    }
+
+    /// Updates the flags of the entry that maps `page` and returns the number of 4KiB pages that
+    /// the entry covers. If `flags` is `None`, the entry is only checked.
+    fn update_flags_inner(&mut self,
+                          page: Page,
+                          flags: Option<EntryFlags>)
+                          -> Result<usize, FlagUpdateError> {
+        let access = self.access;
+        let p3 = self.p4_mut()
+            .next_table_mut(page.p4_index(), &access)
+            .ok_or(FlagUpdateError::PageNotMapped)?;
+        if p3[page.p3_index()].flags().contains(HUGE_PAGE) {
+            if page.number % (ENTRY_COUNT * ENTRY_COUNT) != 0 {
+                return Err(FlagUpdateError::ParentEntryHugePage);
+            }
+            if let Some(flags) = flags {
+                p3[page.p3_index()].set_flags(flags | PRESENT | HUGE_PAGE);
+            }
+            return Ok(ENTRY_COUNT * ENTRY_COUNT);
+        }
+
+        let p2 = p3.next_table_mut(page.p3_index(), &access)
+            .ok_or(FlagUpdateError::PageNotMapped)?;
+        if p2[page.p2_index()].flags().contains(HUGE_PAGE) {
+            if page.number % ENTRY_COUNT != 0 {
+                return Err(FlagUpdateError::ParentEntryHugePage);
+            }
+            if let Some(flags) = flags {
+                p2[page.p2_index()].set_flags(flags | PRESENT | HUGE_PAGE);
+            }
+            return Ok(ENTRY_COUNT);
+        }
+
+        let p1 = p2.next_table_mut(page.p2_index(), &access)
+            .ok_or(FlagUpdateError::PageNotMapped)?;
+        if p1[page.p1_index()].pointed_frame().is_none() {
+            return Err(FlagUpdateError::PageNotMapped);
+        }
+        if let Some(flags) = flags {
+            p1[page.p1_index()].set_flags(flags | PRESENT);
+        }
+        Ok(1)
+    }
+}
+
+impl<T> Mapper for MappedPageTable<T>
+    where T: TableAccess
+{
+    fn translate_page(&self, page: Page) -> Option<Frame> {
+        let access = self.access;
+        let p3 = self.p4().next_table(page.p4_index(), &access);
 
         let huge_page = || {
             p3.and_then(|p3| {
@@ -53,7 +407,7 @@ impl Mapper {
                         });
                     }
                 }
-                if let Some(p2) = p3.next_table(page.p3_index()) {
+                if let Some(p2) = p3.next_table(page.p3_index(), &access) {
                     let p2_entry = &p2[page.p2_index()];
                     // 2MiB page?
                     if let Some(start_frame) = p2_entry.pointed_frame() {
@@ -68,51 +422,399 @@ impl Mapper {
             })
         };
 
-        p3.and_then(|p3| p3.next_table(page.p3_index()))
-            .and_then(|p2| p2.next_table(page.p2_index()))
+        p3.and_then(|p3| p3.next_table(page.p3_index(), &access))
+            .and_then(|p2| p2.next_table(page.p2_index(), &access))
             .and_then(|p1| p1[page.p1_index()].pointed_frame())
             .or_else(huge_page)
     }
 
-    pub fn map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A)
+    fn map_to<A>(&mut self,
+                 page: Page,
+                 frame: Frame,
+                 flags: EntryFlags,
+                 allocator: &mut A)
+                 -> Result<MapperFlush, MapToError>
         where A: FrameAllocator
     {
-        let mut p3 = self.p4_mut().next_table_create(page.p4_index(), allocator);
-        let mut p2 = p3.next_table_create(page.p3_index(), allocator);
-        let mut p1 = p2.next_table_create(page.p2_index(), allocator);
+        self.create_tables(page, 3, allocator)?;
+        let access = self.access;
+        let p1 = self.p4_mut()
+            .next_table_mut(page.p4_index(), &access)
+            .and_then(|p3| p3.next_table_mut(page.p3_index(), &access))
+            .and_then(|p2| p2.next_table_mut(page.p2_index(), &access))
+            .expect("page tables were just created");
 
-        assert!(p1[page.p1_index()].is_unused());
+        if !p1[page.p1_index()].is_unused() {
+            return Err(MapToError::AlreadyMapped);
+        }
         p1[page.p1_index()].set(frame, flags | PRESENT);
+        Ok(MapperFlush::new(page))
     }
 
-    pub fn map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A)
+    fn map_to_2mib<A>(&mut self,
+                      page: Page,
+                      frame: Frame,
+                      flags: EntryFlags,
+                      allocator: &mut A)
+                      -> Result<MapperFlush, MapToError>
         where A: FrameAllocator
     {
-        let frame = allocator.allocate_frame().expect("out of memory");
-        self.map_to(page, frame, flags, allocator)
+        if page.number % ENTRY_COUNT != 0 || frame.number % ENTRY_COUNT != 0 {
+            return Err(MapToError::NotAligned);
+        }
+
+        self.create_tables(page, 2, allocator)?;
+        let access = self.access;
+        let p2 = self.p4_mut()
+            .next_table_mut(page.p4_index(), &access)
+            .and_then(|p3| p3.next_table_mut(page.p3_index(), &access))
+            .expect("page tables were just created");
+
+        if !p2[page.p2_index()].is_unused() {
+            return Err(MapToError::AlreadyMapped);
+        }
+        p2[page.p2_index()].set(frame, flags | PRESENT | HUGE_PAGE);
+        Ok(MapperFlush::new(page))
     }
 
-    pub fn identity_map<A>(&mut self, frame: Frame, flags: EntryFlags, allocator: &mut A)
+    fn map_to_1gib<A>(&mut self,
+                      page: Page,
+                      frame: Frame,
+                      flags: EntryFlags,
+                      allocator: &mut A)
+                      -> Result<MapperFlush, MapToError>
         where A: FrameAllocator
     {
-        let page = Page::containing_address(frame.start_address());
-        self.map_to(page, frame, flags, allocator)
+        if !supports_1gib_pages() {
+            return Err(MapToError::HugePageNotSupported);
+        }
+        if page.number % (ENTRY_COUNT * ENTRY_COUNT) != 0 ||
+           frame.number % (ENTRY_COUNT * ENTRY_COUNT) != 0 {
+            return Err(MapToError::NotAligned);
+        }
+
+        self.create_tables(page, 1, allocator)?;
+        let access = self.access;
+        let p3 = self.p4_mut()
+            .next_table_mut(page.p4_index(), &access)
+            .expect("page table was just created");
+
+        if !p3[page.p3_index()].is_unused() {
+            return Err(MapToError::AlreadyMapped);
+        }
+        p3[page.p3_index()].set(frame, flags | PRESENT | HUGE_PAGE);
+        Ok(MapperFlush::new(page))
     }
 
-    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
+    fn unmap<A>(&mut self,
+                page: Page,
+                allocator: &mut A)
+                -> Result<(Frame, MapperFlush), UnmapError>
         where A: FrameAllocator
     {
-        assert!(self.translate(page.start_address()).is_some());
+        let frame = self.unmap_entry(page)?;
+        self.free_empty_tables(page, 0, allocator);
+        Ok((frame, MapperFlush::new(page)))
+    }
 
-        let p1 = self.p4_mut()
-            .next_table_mut(page.p4_index())
-            .and_then(|p3| p3.next_table_mut(page.p3_index()))
-            .and_then(|p2| p2.next_table_mut(page.p2_index()))
-            .expect("mapping code does not support huge pages");
-        let frame = p1[page.p1_index()].pointed_frame().unwrap();
-        p1[page.p1_index()].set_unused();
-        unsafe { ::x86::shared::tlb::flush(page.start_address()) };
-        // TODO free p(1,2,3) table if empty
-        // allocator.deallocate_frame(frame);
+    fn update_flags(&mut self,
+                    page: Page,
+                    flags: EntryFlags)
+                    -> Result<MapperFlush, FlagUpdateError> {
+        self.update_flags_inner(page, Some(flags)).map(|_| MapperFlush::new(page))
+    }
+
+    fn update_flags_range(&mut self,
+                          start: Page,
+                          end: Page,
+                          flags: EntryFlags)
+                          -> Result<FlushBatch, FlagUpdateError> {
+        // check the whole range first, so that an error leaves all pages unchanged
+        let mut page = start;
+        while page <= end {
+            page.number += self.update_flags_inner(page, None)?;
+        }
+
+        let mut batch = FlushBatch::new();
+        let mut page = start;
+        while page <= end {
+            let page_count = self.update_flags_inner(page, Some(flags))
+                .expect("the range was checked before");
+            batch.add(MapperFlush::new(page));
+            page.number += page_count;
+        }
+        Ok(batch)
+    }
+}
+
+/// Returns whether the CPU supports 1GiB pages (the `pdpe1gb` CPUID feature). The CPUID
+/// instruction is only executed on the first call.
+pub fn supports_1gib_pages() -> bool {
+    static SUPPORTS_1GIB_PAGES: Once<bool> = Once::new();
+
+    *SUPPORTS_1GIB_PAGES.call_once(|| {
+        // the feature bit is in leaf 0x8000_0001, which older CPUs don't implement
+        let (max_extended_leaf, _, _, _) = cpuid(0x8000_0000);
+        if max_extended_leaf < 0x8000_0001 {
+            return false;
+        }
+        let (_, _, _, edx) = cpuid(0x8000_0001);
+        edx & (1 << 26) != 0
+    })
+}
+
+/// Executes the CPUID instruction for the given leaf and returns `(eax, ebx, ecx, edx)`.
+fn cpuid(leaf: u32) -> (u32, u32, u32, u32) {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid"
+             : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+             : "{eax}"(leaf), "{ecx}"(0)
+             :
+             : "intel");
+    }
+    (eax, ebx, ecx, edx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::{PAGE_SIZE, Frame, FrameAllocator};
+    use memory::paging::{Page, PhysAddr, VirtAddr, PRESENT, WRITABLE, NO_EXECUTE};
+    use std::vec::Vec;
+
+    /// Simulated physical memory that backs the page tables. Frames are handed out in
+    /// ascending order.
+    struct TestMemory {
+        memory: Vec<u64>,
+        frame_count: usize,
+        next_frame: usize,
+        free_frames: Vec<usize>,
+    }
+
+    impl TestMemory {
+        fn new(frame_count: usize) -> TestMemory {
+            TestMemory {
+                memory: vec![0; frame_count * PAGE_SIZE / 8],
+                frame_count: frame_count,
+                next_frame: 0,
+                free_frames: Vec::new(),
+            }
+        }
+
+        /// Creates a mapper for a new, empty page table in the simulated memory.
+        fn mapper(&mut self) -> OffsetPageTable {
+            let p4_frame = self.allocate_frame().unwrap();
+            unsafe { OffsetPageTable::from_p4_frame(p4_frame, self.memory.as_ptr() as usize) }
+        }
+
+        fn used_frames(&self) -> usize {
+            self.next_frame - self.free_frames.len()
+        }
+    }
+
+    impl FrameAllocator for TestMemory {
+        fn allocate_frame(&mut self) -> Option<Frame> {
+            if let Some(number) = self.free_frames.pop() {
+                return Some(Frame { number: number });
+            }
+            if self.next_frame < self.frame_count {
+                self.next_frame += 1;
+                Some(Frame { number: self.next_frame - 1 })
+            } else {
+                None
+            }
+        }
+
+        fn deallocate_frame(&mut self, frame: Frame) {
+            assert!(frame.number < self.next_frame && !self.free_frames.contains(&frame.number));
+            self.free_frames.push(frame.number);
+        }
+    }
+
+    fn page(address: usize) -> Page {
+        Page::containing_address(VirtAddr::new(address))
+    }
+
+    fn frame(address: usize) -> Frame {
+        Frame::containing_address(PhysAddr::new(address))
+    }
+
+    #[test]
+    fn map_and_translate() {
+        let mut memory = TestMemory::new(16);
+        let mut mapper = memory.mapper();
+
+        mapper.map_to(page(0xdead_b000), frame(0x42000), WRITABLE, &mut memory)
+            .unwrap()
+            .ignore();
+        assert_eq!(mapper.translate(VirtAddr::new(0xdead_beef)),
+                   Some(PhysAddr::new(0x42eef)));
+        assert_eq!(mapper.translate(VirtAddr::new(0xdead_c000)), None);
+        // the P4 table and one P3, P2 and P1 table
+        assert_eq!(memory.used_frames(), 4);
+
+        let result = mapper.map_to(page(0xdead_b000), frame(0x43000), WRITABLE, &mut memory);
+        assert_eq!(result.err(), Some(MapToError::AlreadyMapped));
+    }
+
+    #[test]
+    fn unmap_frees_empty_tables() {
+        let mut memory = TestMemory::new(16);
+        let mut mapper = memory.mapper();
+
+        mapper.map_to(page(0x1000), frame(0x42000), WRITABLE, &mut memory).unwrap().ignore();
+        mapper.map_to(page(0x2000), frame(0x43000), WRITABLE, &mut memory).unwrap().ignore();
+        assert_eq!(memory.used_frames(), 4);
+
+        let (unmapped, flush) = mapper.unmap(page(0x1000), &mut memory).unwrap();
+        flush.ignore();
+        assert_eq!(unmapped, frame(0x42000));
+        assert_eq!(memory.used_frames(), 4);
+
+        mapper.unmap(page(0x2000), &mut memory).unwrap().1.ignore();
+        assert_eq!(memory.used_frames(), 1);
+
+        let result = mapper.unmap(page(0x2000), &mut memory);
+        assert_eq!(result.err(), Some(UnmapError::PageNotMapped));
+    }
+
+    #[test]
+    fn unmap_keep_tables() {
+        let mut memory = TestMemory::new(16);
+        let mut mapper = memory.mapper();
+
+        mapper.map_to(page(0x1000), frame(0x42000), WRITABLE, &mut memory).unwrap().ignore();
+        let (unmapped, flush) = mapper.unmap_keep_tables(page(0x1000)).unwrap();
+        flush.ignore();
+        assert_eq!(unmapped, frame(0x42000));
+        assert_eq!(mapper.translate_page(page(0x1000)), None);
+        assert_eq!(memory.used_frames(), 4);
+
+        // mapping the page again reuses the tables
+        let mut no_frames = TestMemory::new(0);
+        mapper.map_to(page(0x1000), frame(0x43000), WRITABLE, &mut no_frames).unwrap().ignore();
+        assert_eq!(mapper.translate_page(page(0x1000)), Some(frame(0x43000)));
+    }
+
+    #[test]
+    fn map_fails_without_frames() {
+        let mut memory = TestMemory::new(2);
+        let mut mapper = memory.mapper();
+
+        let result = mapper.map_to(page(0x1000), frame(0x42000), WRITABLE, &mut memory);
+        assert_eq!(result.err(), Some(MapToError::FrameAllocationFailed));
+        // the P3 table that was created before the allocation failed is freed again
+        assert_eq!(memory.used_frames(), 1);
+    }
+
+    #[test]
+    fn failed_map_keeps_existing_tables() {
+        let mut memory = TestMemory::new(5);
+        let mut mapper = memory.mapper();
+
+        mapper.map_to(page(0x1000), frame(0x42000), WRITABLE, &mut memory).unwrap().ignore();
+        assert_eq!(memory.used_frames(), 4);
+        // needs a new P2 and P1 table, but only one frame is left
+        let result = mapper.map_to(page(0x4000_0000), frame(0x43000), WRITABLE, &mut memory);
+        assert_eq!(result.err(), Some(MapToError::FrameAllocationFailed));
+        assert_eq!(memory.used_frames(), 4);
+        assert_eq!(mapper.translate_page(page(0x1000)), Some(frame(0x42000)));
+    }
+
+    #[test]
+    fn huge_page() {
+        let mut memory = TestMemory::new(16);
+        let mut mapper = memory.mapper();
+
+        mapper.map_to_2mib(page(0x4000_0000), frame(0x20_0000), WRITABLE, &mut memory)
+            .unwrap()
+            .ignore();
+        assert_eq!(mapper.translate(VirtAddr::new(0x4012_3456)),
+                   Some(PhysAddr::new(0x32_3456)));
+
+        let result = mapper.map_to(page(0x4000_1000), frame(0x42000), WRITABLE, &mut memory);
+        assert_eq!(result.err(), Some(MapToError::ParentEntryHugePage));
+        let result = mapper.unmap(page(0x4000_1000), &mut memory);
+        assert_eq!(result.err(), Some(UnmapError::ParentEntryHugePage));
+
+        let (unmapped, flush) = mapper.unmap(page(0x4000_0000), &mut memory).unwrap();
+        flush.ignore();
+        assert_eq!(unmapped, frame(0x20_0000));
+        assert_eq!(memory.used_frames(), 1);
+    }
+
+    #[test]
+    fn huge_page_alignment() {
+        let mut memory = TestMemory::new(16);
+        let mut mapper = memory.mapper();
+
+        let result = mapper.map_to_2mib(page(0x4000_1000), frame(0x20_0000), WRITABLE, &mut memory);
+        assert_eq!(result.err(), Some(MapToError::NotAligned));
+        let result = mapper.map_to_2mib(page(0x4000_0000), frame(0x20_1000), WRITABLE, &mut memory);
+        assert_eq!(result.err(), Some(MapToError::NotAligned));
+        // nothing was mapped, so no page tables were allocated
+        assert_eq!(memory.used_frames(), 1);
+    }
+
+    #[test]
+    fn update_flags_and_mappings() {
+        let mut memory = TestMemory::new(16);
+        let mut mapper = memory.mapper();
+
+        mapper.map_to(page(0x1000), frame(0x42000), WRITABLE, &mut memory).unwrap().ignore();
+        mapper.map_to(page(0x2000), frame(0x43000), WRITABLE, &mut memory).unwrap().ignore();
+        mapper.update_flags(page(0x2000), NO_EXECUTE).unwrap().ignore();
+
+        let mappings: Vec<_> = mapper.mappings().collect();
+        assert_eq!(mappings.len(), 2);
+        assert_eq!(mappings[0].start, VirtAddr::new(0x1000));
+        assert_eq!(mappings[0].frame_start, PhysAddr::new(0x42000));
+        assert_eq!(mappings[0].flags, PRESENT | WRITABLE);
+        assert_eq!(mappings[1].flags, PRESENT | NO_EXECUTE);
+
+        // contiguous mappings with the same flags are merged
+        mapper.update_flags(page(0x2000), WRITABLE).unwrap().ignore();
+        let mappings: Vec<_> = mapper.mappings().collect();
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(mappings[0].size, 2 * PAGE_SIZE);
+
+        let result = mapper.update_flags(page(0x3000), WRITABLE);
+        assert_eq!(result.err(), Some(FlagUpdateError::PageNotMapped));
+    }
+
+    #[test]
+    fn update_flags_range() {
+        let mut memory = TestMemory::new(16);
+        let mut mapper = memory.mapper();
+
+        mapper.map_to(page(0x1000), frame(0x42000), WRITABLE, &mut memory).unwrap().ignore();
+        mapper.map_to(page(0x2000), frame(0x43000), WRITABLE, &mut memory).unwrap().ignore();
+        mapper.map_to(page(0x4000), frame(0x44000), WRITABLE, &mut memory).unwrap().ignore();
+
+        // page 0x3000 is not mapped, so nothing is changed
+        let result = mapper.update_flags_range(page(0x1000), page(0x4000), NO_EXECUTE);
+        assert_eq!(result.err(), Some(FlagUpdateError::PageNotMapped));
+        assert!(mapper.mappings().all(|mapping| mapping.flags == PRESENT | WRITABLE));
+
+        mapper.update_flags_range(page(0x1000), page(0x2000), NO_EXECUTE).unwrap().flush();
+        let mappings: Vec<_> = mapper.mappings().collect();
+        assert_eq!(mappings.len(), 2);
+        assert_eq!(mappings[0].flags, PRESENT | NO_EXECUTE);
+        assert_eq!(mappings[0].size, 2 * PAGE_SIZE);
+        assert_eq!(mappings[1].flags, PRESENT | WRITABLE);
+    }
+
+    #[test]
+    fn mappings_of_recursive_entry_slot() {
+        let mut memory = TestMemory::new(16);
+        let mut mapper = memory.mapper();
+
+        // P4 entry 510 is only special for recursively mapped tables
+        let address = 0o_177777_776_000_000_000_0000;
+        mapper.map_to(page(address), frame(0x42000), WRITABLE, &mut memory).unwrap().ignore();
+        let mappings: Vec<_> = mapper.mappings().collect();
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(mappings[0].start, VirtAddr::new(address));
     }
 }
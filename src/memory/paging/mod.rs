@@ -7,22 +7,48 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+pub use self::address::{PhysAddr, VirtAddr};
 pub use self::entry::*;
-use memory::{PAGE_SIZE, Frame, FrameAllocator};
+pub use self::flush::{MapperFlush, FlushBatch};
+#[cfg(any(test, feature = "page-table-dump"))]
+pub use self::walker::{Mapping, MappingIter};
+use memory::PAGE_SIZE;
+#[cfg(not(test))]
+use memory::{KERNEL_OFFSET, Frame, FrameAllocator, kernel_to_physical};
+#[cfg(all(not(feature = "physical-memory-map"), not(test)))]
 use self::temporary_page::TemporaryPage;
-pub use self::mapper::Mapper;
+pub use self::mapper::{Mapper, MappedPageTable, OffsetPageTable, MapToError, UnmapError,
+                       FlagUpdateError, supports_1gib_pages};
+#[cfg(not(test))]
+pub use self::mapper::RecursivePageTable;
+pub use self::table::{TableAccess, RecursiveAccess, OffsetAccess};
+#[cfg(not(test))]
 use core::ops::{Deref, DerefMut};
+#[cfg(not(test))]
 use multiboot2::BootInformation;
 
+mod address;
 mod entry;
+mod flush;
 mod table;
+#[cfg(all(not(feature = "physical-memory-map"), not(test)))]
 mod temporary_page;
 mod mapper;
+// the walker is only needed to dump page tables
+#[cfg(any(test, feature = "page-table-dump"))]
+mod walker;
 
 const ENTRY_COUNT: usize = 512;
 
-pub type PhysicalAddress = usize;
-pub type VirtualAddress = usize;
+/// The virtual address at which `map_physical_memory` maps the physical memory.
+pub const PHYSICAL_MEMORY_OFFSET: usize = 0xffff_8000_0000_0000;
+
+/// Returns the address at which `address` is accessible in the physical memory window.
+///
+/// The returned address is only valid after `map_physical_memory` was called.
+pub fn phys_to_virt(address: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET + address.as_usize())
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Page {
@@ -30,15 +56,12 @@ pub struct Page {
 }
 
 impl Page {
-    pub fn containing_address(address: VirtualAddress) -> Page {
-        assert!(address < 0x0000_8000_0000_0000 || address >= 0xffff_8000_0000_0000,
-                "invalid address: 0x{:x}",
-                address);
-        Page { number: address / PAGE_SIZE }
+    pub fn containing_address(address: VirtAddr) -> Page {
+        Page { number: address.as_usize() / PAGE_SIZE }
     }
 
-    fn start_address(&self) -> usize {
-        self.number * PAGE_SIZE
+    pub fn start_address(&self) -> VirtAddr {
+        VirtAddr::new(self.number * PAGE_SIZE)
     }
 
     fn p4_index(&self) -> usize {
@@ -62,6 +85,7 @@ impl Page {
     }
 }
 
+#[derive(Clone)]
 pub struct PageIter {
     start: Page,
     end: Page,
@@ -81,53 +105,60 @@ impl Iterator for PageIter {
     }
 }
 
+#[cfg(not(test))]
 pub struct ActivePageTable {
-    mapper: Mapper,
+    mapper: RecursivePageTable,
 }
 
+#[cfg(not(test))]
 impl Deref for ActivePageTable {
-    type Target = Mapper;
+    type Target = RecursivePageTable;
 
-    fn deref(&self) -> &Mapper {
+    fn deref(&self) -> &RecursivePageTable {
         &self.mapper
     }
 }
 
+#[cfg(not(test))]
 impl DerefMut for ActivePageTable {
-    fn deref_mut(&mut self) -> &mut Mapper {
+    fn deref_mut(&mut self) -> &mut RecursivePageTable {
         &mut self.mapper
     }
 }
 
+#[cfg(not(test))]
 impl ActivePageTable {
     unsafe fn new() -> ActivePageTable {
-        ActivePageTable { mapper: Mapper::new() }
+        ActivePageTable { mapper: RecursivePageTable::new() }
     }
 
+    #[cfg(not(feature = "physical-memory-map"))]
     pub fn with<F>(&mut self,
                    table: &mut InactivePageTable,
                    temporary_page: &mut temporary_page::TemporaryPage, // new
                    f: F)
-        where F: FnOnce(&mut Mapper)
+        where F: FnOnce(&mut RecursivePageTable)
     {
-        use x86::shared::{control_regs, tlb};
+        use x86::shared::control_regs;
+        use self::flush::tlb;
         let flush_tlb = || unsafe { tlb::flush_all() };
 
         {
-            let backup = Frame::containing_address(unsafe { control_regs::cr3() } as usize);
+            let backup =
+                Frame::containing_address(PhysAddr::new(unsafe { control_regs::cr3() } as usize));
 
             // map temporary_page to current p4 table
             let p4_table = temporary_page.map_table_frame(backup.clone(), self);
 
             // overwrite recursive mapping
-            self.p4_mut()[511].set(table.p4_frame.clone(), PRESENT | WRITABLE);
+            self.p4_mut()[table::RECURSIVE_INDEX].set(table.p4_frame.clone(), PRESENT | WRITABLE);
             flush_tlb();
 
             // execute f in the new context
             f(self);
 
             // restore recursive mapping to original p4 table
-            p4_table[511].set(backup, PRESENT | WRITABLE);
+            p4_table[table::RECURSIVE_INDEX].set(backup, PRESENT | WRITABLE);
             flush_tlb();
         }
 
@@ -138,20 +169,24 @@ impl ActivePageTable {
         use x86::shared::control_regs;
 
         let old_table = InactivePageTable {
-            p4_frame: Frame::containing_address(unsafe { control_regs::cr3() } as usize),
+            p4_frame: Frame::containing_address(PhysAddr::new(unsafe { control_regs::cr3() } as
+                                                              usize)),
         };
         unsafe {
-            control_regs::cr3_write(new_table.p4_frame.start_address());
+            control_regs::cr3_write(new_table.p4_frame.start_address().as_usize());
         }
         old_table
     }
 }
 
+#[cfg(not(test))]
 pub struct InactivePageTable {
     p4_frame: Frame,
 }
 
+#[cfg(not(test))]
 impl InactivePageTable {
+    #[cfg(not(feature = "physical-memory-map"))]
     pub fn new(frame: Frame,
                active_table: &mut ActivePageTable,
                temporary_page: &mut TemporaryPage)
@@ -159,18 +194,38 @@ impl InactivePageTable {
         {
             let table = temporary_page.map_table_frame(frame.clone(), active_table);
             table.zero();
-            table[511].set(frame.clone(), PRESENT | WRITABLE);
+            table[table::RECURSIVE_INDEX].set(frame.clone(), PRESENT | WRITABLE);
         }
         temporary_page.unmap(active_table);
 
         InactivePageTable { p4_frame: frame }
     }
+
+    /// Returns a mapper that edits this table through the physical memory window, without
+    /// switching to it or mapping it temporarily like `ActivePageTable::with`.
+    ///
+    /// This is unsafe because all physical memory must be mapped at `physical_memory_offset`, see
+    /// `map_physical_memory`.
+    #[cfg_attr(not(feature = "physical-memory-map"), allow(dead_code))]
+    pub unsafe fn mapper(&mut self, physical_memory_offset: usize) -> OffsetPageTable {
+        OffsetPageTable::from_p4_frame(self.p4_frame.clone(), physical_memory_offset)
+    }
 }
 
+/// The kernel's page table after `remap_the_kernel`. With the `physical-memory-map` feature, its
+/// tables are accessed through the physical memory window instead of the recursive mapping.
+#[cfg(all(not(feature = "physical-memory-map"), not(test)))]
+pub type KernelPageTable = ActivePageTable;
+#[cfg(all(feature = "physical-memory-map", not(test)))]
+pub type KernelPageTable = OffsetPageTable;
+
+#[cfg(all(not(feature = "physical-memory-map"), not(test)))]
 pub fn remap_the_kernel<A>(allocator: &mut A, boot_info: &BootInformation) -> ActivePageTable
     where A: FrameAllocator
 {
-    let mut temporary_page = TemporaryPage::new(Page { number: 0xcafebabe }, allocator);
+    let temporary_page_address = VirtAddr::new(0xffff_fe00_cafe_b000);
+    let mut temporary_page = TemporaryPage::new(Page::containing_address(temporary_page_address),
+                                                allocator);
 
     let mut active_table = unsafe { ActivePageTable::new() };
     let mut new_table = {
@@ -178,50 +233,226 @@ pub fn remap_the_kernel<A>(allocator: &mut A, boot_info: &BootInformation) -> Ac
         InactivePageTable::new(frame, &mut active_table, &mut temporary_page)
     };
 
+    // `with` flushes the whole TLB when it returns, so the flushes of the mapper can be ignored
     active_table.with(&mut new_table, &mut temporary_page, |mapper| {
-        let elf_sections_tag = boot_info.elf_sections_tag()
-            .expect("Memory map tag required");
-
-        // identity map the allocated kernel sections
-        for section in elf_sections_tag.sections() {
-            if !section.is_allocated() {
-                // section is not loaded to memory
-                continue;
-            }
-
-            assert!(section.addr as usize % PAGE_SIZE == 0,
-                    "sections need to be page aligned");
-            println!("mapping section at addr: {:#x}, size: {:#x}",
-                     section.addr,
-                     section.size);
-
-            let flags = EntryFlags::from_elf_section_flags(section);
-
-            let start_frame = Frame::containing_address(section.start_address());
-            let end_frame = Frame::containing_address(section.end_address() - 1);
-            for frame in Frame::range_inclusive(start_frame, end_frame) {
-                mapper.identity_map(frame, flags, allocator);
-            }
-        }
+        map_kernel(mapper, boot_info, allocator);
+    });
+
+    let old_table = active_table.switch(new_table);
+
+    // the old P4 table is part of the kernel's .bss section, its page becomes a guard page
+    let old_p4_page = kernel_page(&old_table.p4_frame);
+    let (_, flush) = active_table.unmap(old_p4_page, allocator)
+        .expect("failed to unmap old P4 table");
+    flush.flush();
+
+    active_table
+}
+
+/// Maps all physical memory at `PHYSICAL_MEMORY_OFFSET` and creates the new page table through
+/// this window. The new table has no recursive entry, so the returned mapper is the only way to
+/// access its tables.
+#[cfg(all(feature = "physical-memory-map", not(test)))]
+pub fn remap_the_kernel<A>(allocator: &mut A, boot_info: &BootInformation) -> OffsetPageTable
+    where A: FrameAllocator
+{
+    use self::table::{Table, Level4};
+    use x86::shared::control_regs;
+
+    let memory_end = boot_info.memory_map_tag()
+        .expect("Memory map tag required")
+        .memory_areas()
+        .map(|area| (area.base_addr + area.length) as usize)
+        .max()
+        .expect("no memory areas");
+    let memory_end = PhysAddr::new(memory_end);
+
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let boot_table = unsafe { map_physical_memory(&mut active_table, memory_end, allocator) };
 
-        // identity map the VGA text buffer
-        let vga_buffer_frame = Frame::containing_address(0xb8000);
-        mapper.identity_map(vga_buffer_frame, WRITABLE, allocator);
+    let mut new_table = {
+        let frame = allocator.allocate_frame().expect("no more frames");
+        let table = phys_to_virt(frame.start_address()).as_usize() as *mut Table<Level4>;
+        unsafe { (*table).zero() };
+        InactivePageTable { p4_frame: frame }
+    };
 
-        // identity map the multiboot info structure
-        let multiboot_start = Frame::containing_address(boot_info.start_address());
-        let multiboot_end = Frame::containing_address(boot_info.end_address() - 1);
-        for frame in Frame::range_inclusive(multiboot_start, multiboot_end) {
-            mapper.identity_map(frame, PRESENT, allocator);
+    {
+        // the new table is not active yet, so the flushes of the mapper can be ignored
+        let mut mapper = unsafe { new_table.mapper(PHYSICAL_MEMORY_OFFSET) };
+        map_kernel(&mut mapper, boot_info, allocator);
+
+        // share the tables of the physical memory window with the boot table
+        let window_start = Page::containing_address(phys_to_virt(PhysAddr::new(0)));
+        let window_end = Page::containing_address(phys_to_virt(memory_end - 1));
+        for index in window_start.p4_index()..(window_end.p4_index() + 1) {
+            let entry = &boot_table.p4()[index];
+            mapper.p4_mut()[index].set(entry.pointed_frame().unwrap(), entry.flags());
         }
-    });
+    }
 
     let old_table = active_table.switch(new_table);
-    println!("NEW TABLE!!!");
 
-    let old_p4_page = Page::containing_address(old_table.p4_frame.start_address());
-    active_table.unmap(old_p4_page, allocator);
-    println!("guard page at {:#x}", old_p4_page.start_address());
+    let p4_frame =
+        Frame::containing_address(PhysAddr::new(unsafe { control_regs::cr3() } as usize));
+    let mut kernel_table =
+        unsafe { OffsetPageTable::from_p4_frame(p4_frame, PHYSICAL_MEMORY_OFFSET) };
 
-    active_table
+    // the old P4 table is part of the kernel's .bss section, its page becomes a guard page
+    let old_p4_page = kernel_page(&old_table.p4_frame);
+    let (_, flush) = kernel_table.unmap(old_p4_page, allocator)
+        .expect("failed to unmap old P4 table");
+    flush.flush();
+
+    kernel_table
+}
+
+/// Maps the kernel sections, the VGA text buffer and the multiboot information structure to the
+/// higher half. The returned flushes are ignored, so `mapper` must not be the active table.
+#[cfg(not(test))]
+fn map_kernel<M, A>(mapper: &mut M, boot_info: &BootInformation, allocator: &mut A)
+    where M: Mapper,
+          A: FrameAllocator
+{
+    let elf_sections_tag = boot_info.elf_sections_tag()
+        .expect("Memory map tag required");
+
+    // map the allocated kernel sections to the higher half
+    for section in elf_sections_tag.sections() {
+        if !section.is_allocated() {
+            // section is not loaded to memory
+            continue;
+        }
+        if (section.addr as usize) < KERNEL_OFFSET {
+            // the boot code is not needed after the jump to the higher half
+            continue;
+        }
+
+        assert!(section.addr as usize % PAGE_SIZE == 0,
+                "sections need to be page aligned");
+        println!("mapping section at addr: {:#x}, size: {:#x}",
+                 section.addr,
+                 section.size);
+
+        let flags = EntryFlags::from_elf_section_flags(section);
+
+        let start_frame = Frame::containing_address(kernel_to_physical(section.start_address()));
+        let end_frame = Frame::containing_address(kernel_to_physical(section.end_address() - 1));
+        for frame in Frame::range_inclusive(start_frame, end_frame) {
+            mapper.map_to(kernel_page(&frame), frame, flags, allocator)
+                .expect("failed to map kernel section")
+                .ignore();
+        }
+    }
+
+    // map the VGA text buffer to the higher half
+    let vga_buffer_frame = Frame::containing_address(PhysAddr::new(0xb8000));
+    mapper.map_to(kernel_page(&vga_buffer_frame), vga_buffer_frame, WRITABLE, allocator)
+        .expect("failed to map VGA buffer")
+        .ignore();
+
+    // map the multiboot info structure to the higher half
+    let multiboot_start = Frame::containing_address(kernel_to_physical(boot_info.start_address()));
+    let multiboot_end =
+        Frame::containing_address(kernel_to_physical(boot_info.end_address() - 1));
+    for frame in Frame::range_inclusive(multiboot_start, multiboot_end) {
+        mapper.map_to(kernel_page(&frame), frame, PRESENT, allocator)
+            .expect("failed to map multiboot info structure")
+            .ignore();
+    }
+}
+
+/// Returns the page that maps `frame` in the higher half kernel mapping.
+#[cfg(not(test))]
+fn kernel_page(frame: &Frame) -> Page {
+    Page::containing_address(VirtAddr::new(frame.start_address().as_usize() + KERNEL_OFFSET))
+}
+
+/// Maps all physical memory below `end` at `PHYSICAL_MEMORY_OFFSET` using huge pages and returns
+/// a mapper that accesses the active page table through this window instead of the recursive
+/// mapping. Unlike the recursive mapping, the window allows editing any table directly.
+///
+/// This is unsafe because the returned mapper and `active_table` modify the same page tables, so
+/// only one of them may be used at a time.
+#[cfg(not(test))]
+#[cfg_attr(not(feature = "physical-memory-map"), allow(dead_code))]
+pub unsafe fn map_physical_memory<A>(active_table: &mut ActivePageTable,
+                                     end: PhysAddr,
+                                     allocator: &mut A)
+                                     -> OffsetPageTable
+    where A: FrameAllocator
+{
+    use x86::shared::control_regs;
+
+    let use_1gib_pages = supports_1gib_pages();
+    let frames_per_page = if use_1gib_pages {
+        ENTRY_COUNT * ENTRY_COUNT
+    } else {
+        ENTRY_COUNT
+    };
+    let frame_count = end.align_up(frames_per_page * PAGE_SIZE).as_usize() / PAGE_SIZE;
+
+    let mut flush_batch = FlushBatch::new();
+    let mut number = 0;
+    while number < frame_count {
+        let frame = Frame { number: number };
+        let page = Page::containing_address(phys_to_virt(frame.start_address()));
+        let flags = WRITABLE | NO_EXECUTE;
+        let result = if use_1gib_pages {
+            active_table.map_to_1gib(page, frame, flags, allocator)
+        } else {
+            active_table.map_to_2mib(page, frame, flags, allocator)
+        };
+        flush_batch.add(result.expect("failed to map physical memory"));
+        number += frames_per_page;
+    }
+    flush_batch.flush();
+
+    let p4_frame = Frame::containing_address(PhysAddr::new(control_regs::cr3() as usize));
+    OffsetPageTable::from_p4_frame(p4_frame, PHYSICAL_MEMORY_OFFSET)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{phys_to_virt, Page, PhysAddr, VirtAddr, PHYSICAL_MEMORY_OFFSET};
+    use std::vec::Vec;
+
+    #[test]
+    fn page_indices() {
+        let page = Page::containing_address(VirtAddr::new(0o_177777_777_776_775_774_7777));
+        assert_eq!(page.p4_index(), 0o777);
+        assert_eq!(page.p3_index(), 0o776);
+        assert_eq!(page.p2_index(), 0o775);
+        assert_eq!(page.p1_index(), 0o774);
+
+        let page = Page::containing_address(VirtAddr::new(0o_000001_002_003_004_0000));
+        assert_eq!(page.p4_index(), 1);
+        assert_eq!(page.p3_index(), 2);
+        assert_eq!(page.p2_index(), 3);
+        assert_eq!(page.p1_index(), 4);
+    }
+
+    #[test]
+    fn page_start_address() {
+        let page = Page::containing_address(VirtAddr::new(0xffff_8000_dead_beef));
+        assert_eq!(page.start_address(), VirtAddr::new(0xffff_8000_dead_b000));
+    }
+
+    #[test]
+    fn page_range() {
+        let start = Page::containing_address(VirtAddr::new(0x1000));
+        let end = Page::containing_address(VirtAddr::new(0x3fff));
+        let pages: Vec<Page> = Page::range_inclusive(start, end).collect();
+        assert_eq!(pages.len(), 3);
+        assert_eq!(pages[0], start);
+        assert_eq!(pages[2], end);
+
+        assert_eq!(Page::range_inclusive(end, start).count(), 0);
+    }
+
+    #[test]
+    fn physical_memory_window() {
+        assert_eq!(phys_to_virt(PhysAddr::new(0)), VirtAddr::new(PHYSICAL_MEMORY_OFFSET));
+        assert_eq!(phys_to_virt(PhysAddr::new(0xb8000)), VirtAddr::new(0xffff_8000_000b_8000));
+    }
 }
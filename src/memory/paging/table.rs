@@ -9,11 +9,81 @@
 
 use memory::paging::entry::*;
 use memory::paging::ENTRY_COUNT;
-use memory::FrameAllocator;
+use memory::paging::flush::tlb;
+use memory::paging::mapper::MapToError;
+use memory::{Frame, FrameAllocator};
 use core::ops::{Index, IndexMut};
 use core::marker::PhantomData;
 
-pub const P4: *mut Table<Level4> = 0xffffffff_fffff000 as *mut _;
+/// The P4 entry that maps the P4 table recursively. The last entry is used for the kernel.
+pub const RECURSIVE_INDEX: usize = 510;
+
+pub const P4: *mut Table<Level4> = 0xffffff7f_bfdfe000 as *mut _;
+
+/// Determines how the page tables of a hierarchy are accessed in virtual memory.
+pub trait TableAccess: Copy {
+    /// Returns the virtual address of the table in `frame`, which is referenced by the entry at
+    /// `index` of the table at virtual address `parent`.
+    fn table_address(&self, parent: usize, index: usize, frame: Frame) -> usize;
+
+    /// Called after the table at virtual address `table_address` was freed.
+    fn table_freed(&self, table_address: usize);
+
+    /// Returns the index of the P4 entry that maps the P4 table recursively, if there is one.
+    /// The addresses of this entry are used to access the page tables, not for regular pages.
+    fn recursive_index(&self) -> Option<usize>;
+}
+
+/// Accesses the page tables through the recursive entry of the active P4 table.
+#[derive(Debug, Clone, Copy)]
+pub struct RecursiveAccess;
+
+impl TableAccess for RecursiveAccess {
+    fn table_address(&self, parent: usize, index: usize, _frame: Frame) -> usize {
+        let address = ((parent << 9) | (index << 12)) & 0x0000_ffff_ffff_ffff;
+        // the recursive entry is in the higher half, so the result needs sign extension
+        address | 0xffff_0000_0000_0000
+    }
+
+    fn table_freed(&self, table_address: usize) {
+        // remove the stale translation for the table's virtual address
+        unsafe { tlb::flush(table_address) };
+    }
+
+    fn recursive_index(&self) -> Option<usize> {
+        Some(RECURSIVE_INDEX)
+    }
+}
+
+/// Accesses the page tables through a window that maps all physical memory at a fixed offset.
+#[derive(Debug, Clone, Copy)]
+pub struct OffsetAccess {
+    physical_memory_offset: usize,
+}
+
+impl OffsetAccess {
+    pub fn new(physical_memory_offset: usize) -> OffsetAccess {
+        OffsetAccess { physical_memory_offset: physical_memory_offset }
+    }
+
+    pub fn table_pointer<L: TableLevel>(&self, frame: Frame) -> *mut Table<L> {
+        (self.physical_memory_offset + frame.start_address().as_usize()) as *mut _
+    }
+}
+
+impl TableAccess for OffsetAccess {
+    fn table_address(&self, _parent: usize, _index: usize, frame: Frame) -> usize {
+        self.physical_memory_offset + frame.start_address().as_usize()
+    }
+
+    fn table_freed(&self, _table_address: usize) {
+        // the physical memory window is not changed, so there is nothing to flush
+    }
+
+    fn recursive_index(&self) -> Option<usize> {
+        None
+    }
+}
 
 pub struct Table<L: TableLevel> {
     entries: [Entry; ENTRY_COUNT],
@@ -28,45 +98,87 @@ impl<L> Table<L>
             entry.set_unused();
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| entry.is_unused())
+    }
 }
 
 impl<L> Table<L>
     where L: HierarchicalLevel
 {
-    fn next_table_address(&self, index: usize) -> Option<usize> {
+    fn next_table_address<T>(&self, index: usize, access: &T) -> Option<usize>
+        where T: TableAccess
+    {
         let entry_flags = self[index].flags();
         if entry_flags.contains(PRESENT) && !entry_flags.contains(HUGE_PAGE) {
             let table_address = self as *const _ as usize;
-            Some((table_address << 9) | (index << 12))
+            let frame = self[index].pointed_frame().unwrap();
+            Some(access.table_address(table_address, index, frame))
         } else {
             None
         }
     }
 
-    pub fn next_table(&self, index: usize) -> Option<&Table<L::NextLevel>> {
-        self.next_table_address(index)
+    pub fn next_table<T>(&self, index: usize, access: &T) -> Option<&Table<L::NextLevel>>
+        where T: TableAccess
+    {
+        self.next_table_address(index, access)
             .map(|address| unsafe { &*(address as *const _) })
     }
 
-    pub fn next_table_mut(&mut self, index: usize) -> Option<&mut Table<L::NextLevel>> {
-        self.next_table_address(index)
+    pub fn next_table_mut<T>(&mut self,
+                             index: usize,
+                             access: &T)
+                             -> Option<&mut Table<L::NextLevel>>
+        where T: TableAccess
+    {
+        self.next_table_address(index, access)
             .map(|address| unsafe { &mut *(address as *mut _) })
     }
 
-    pub fn next_table_create<A>(&mut self,
-                                index: usize,
-                                allocator: &mut A)
-                                -> &mut Table<L::NextLevel>
-        where A: FrameAllocator
+    pub fn next_table_create<A, T>(&mut self,
+                                   index: usize,
+                                   allocator: &mut A,
+                                   access: &T)
+                                   -> Result<&mut Table<L::NextLevel>, MapToError>
+        where A: FrameAllocator,
+              T: TableAccess
     {
-        if self.next_table(index).is_none() {
-            assert!(!self.entries[index].flags().contains(HUGE_PAGE),
-                    "mapping code does not support huge pages");
-            let frame = allocator.allocate_frame().expect("no frames available");
+        if self.next_table(index, access).is_none() {
+            if self.entries[index].flags().contains(HUGE_PAGE) {
+                return Err(MapToError::ParentEntryHugePage);
+            }
+            let frame = allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
             self.entries[index].set(frame, PRESENT | WRITABLE);
-            self.next_table_mut(index).unwrap().zero();
+            self.next_table_mut(index, access).unwrap().zero();
+        }
+        Ok(self.next_table_mut(index, access).unwrap())
+    }
+
+    /// Frees the next table at `index` if none of its entries is used anymore. Returns whether
+    /// the table was freed.
+    pub fn free_next_table_if_empty<A, T>(&mut self,
+                                          index: usize,
+                                          allocator: &mut A,
+                                          access: &T)
+                                          -> bool
+        where A: FrameAllocator,
+              T: TableAccess
+    {
+        let table_address = match self.next_table_address(index, access) {
+            Some(address) => address,
+            None => return false,
+        };
+        if !self.next_table(index, access).unwrap().is_empty() {
+            return false;
         }
-        self.next_table_mut(index).unwrap()
+
+        let frame = self[index].pointed_frame().unwrap();
+        self[index].set_unused();
+        access.table_freed(table_address);
+        allocator.deallocate_frame(frame);
+        true
     }
 }
 
@@ -117,3 +229,19 @@ impl HierarchicalLevel for Level3 {
 impl HierarchicalLevel for Level2 {
     type NextLevel = Level1;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::Frame;
+
+    #[test]
+    fn recursive_table_addresses() {
+        let frame = Frame { number: 0 };
+        let p3 = RecursiveAccess.table_address(P4 as usize, 3, frame.clone());
+        assert_eq!(p3, 0xffffff7f_bfc03000);
+        let p2 = RecursiveAccess.table_address(p3, 4, frame);
+        assert_eq!(p2, 0xffffff7f_80604000);
+        assert_eq!(RecursiveAccess.recursive_index(), Some(RECURSIVE_INDEX));
+    }
+}
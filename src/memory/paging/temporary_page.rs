@@ -7,7 +7,7 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use super::{Page, ActivePageTable, VirtualAddress};
+use super::{Page, ActivePageTable, Mapper, VirtAddr};
 use super::table::{Table, Level1};
 use memory::{Frame, FrameAllocator};
 
@@ -28,12 +28,14 @@ impl TemporaryPage {
 
     /// Maps the temporary page to the given frame in the active table.
     /// Returns the start address of the temporary page.
-    pub fn map(&mut self, frame: Frame, active_table: &mut ActivePageTable) -> VirtualAddress {
+    pub fn map(&mut self, frame: Frame, active_table: &mut ActivePageTable) -> VirtAddr {
         use super::entry::WRITABLE;
 
         assert!(active_table.translate_page(self.page).is_none(),
                 "temporary page is already mapped");
-        active_table.map_to(self.page, frame, WRITABLE, &mut self.allocator);
+        active_table.map_to(self.page, frame, WRITABLE, &mut self.allocator)
+            .expect("failed to map temporary page")
+            .flush();
         self.page.start_address()
     }
 
@@ -43,12 +45,17 @@ impl TemporaryPage {
                            frame: Frame,
                            active_table: &mut ActivePageTable)
                            -> &mut Table<Level1> {
-        unsafe { &mut *(self.map(frame, active_table) as *mut Table<Level1>) }
+        unsafe { &mut *(self.map(frame, active_table).as_usize() as *mut Table<Level1>) }
     }
 
     /// Unmaps the temporary page in the active table.
+    ///
+    /// The page tables of the temporary page are kept, so that the next `map` doesn't need new
+    /// frames and the tiny allocator never has to take back more frames than it handed out.
     pub fn unmap(&mut self, active_table: &mut ActivePageTable) {
-        active_table.unmap(self.page, &mut self.allocator)
+        let (_, flush) = active_table.unmap_keep_tables(self.page)
+            .expect("temporary page is not mapped");
+        flush.flush();
     }
 }
 
@@ -0,0 +1,165 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use super::{Page, PhysAddr, VirtAddr};
+use super::entry::*;
+use super::table::{Table, Level4, TableAccess};
+use memory::PAGE_SIZE;
+
+/// The number of pages that are covered by a P4 table (2^36).
+const PAGE_COUNT: usize = 1 << 36;
+
+/// The number of pages that are covered by a single P4, P3 and P2 entry.
+const P4_ENTRY_PAGES: usize = 1 << 27;
+const P3_ENTRY_PAGES: usize = 1 << 18;
+const P2_ENTRY_PAGES: usize = 1 << 9;
+
+/// A contiguous virtual memory region that is mapped to a contiguous physical region with the
+/// same flags.
+///
+/// The `ACCESSED` and `DIRTY` flags are set by the CPU and are ignored.
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+    pub start: VirtAddr,
+    pub frame_start: PhysAddr,
+    pub size: usize,
+    pub flags: EntryFlags,
+}
+
+impl Mapping {
+    /// Returns whether `other` directly follows this mapping, both virtually and physically.
+    fn is_continued_by(&self, other: &Mapping) -> bool {
+        self.start.as_usize() + self.size == other.start.as_usize() &&
+        self.frame_start.as_usize() + self.size == other.frame_start.as_usize() &&
+        self.flags == other.flags
+    }
+}
+
+/// An iterator over all present mappings of a page table, in ascending virtual address order.
+///
+/// Adjacent mappings are merged if they are physically contiguous and have the same flags.
+pub struct MappingIter<'a, T: TableAccess> {
+    p4: &'a Table<Level4>,
+    access: T,
+    // the page index (without sign extension) at which the walk continues
+    next_index: usize,
+    pending: Option<Mapping>,
+}
+
+impl<'a, T> MappingIter<'a, T>
+    where T: TableAccess
+{
+    pub fn new(p4: &'a Table<Level4>, access: T) -> MappingIter<'a, T> {
+        MappingIter {
+            p4: p4,
+            access: access,
+            next_index: 0,
+            pending: None,
+        }
+    }
+
+    /// Returns the next present leaf entry as a `Mapping`.
+    fn next_leaf(&mut self) -> Option<Mapping> {
+        let access = self.access;
+        while self.next_index < PAGE_COUNT {
+            let index = self.next_index;
+            let page = Page { number: sign_extend(index) };
+
+            // skip the page tables themselves
+            if Some(page.p4_index()) == access.recursive_index() {
+                self.next_index = next_boundary(index, P4_ENTRY_PAGES);
+                continue;
+            }
+            let p3 = match self.p4.next_table(page.p4_index(), &access) {
+                Some(p3) => p3,
+                None => {
+                    self.next_index = next_boundary(index, P4_ENTRY_PAGES);
+                    continue;
+                }
+            };
+
+            let p3_entry = &p3[page.p3_index()];
+            if !p3_entry.flags().contains(PRESENT) {
+                self.next_index = next_boundary(index, P3_ENTRY_PAGES);
+                continue;
+            }
+            if p3_entry.flags().contains(HUGE_PAGE) {
+                self.next_index = next_boundary(index, P3_ENTRY_PAGES);
+                return Some(leaf_mapping(page, p3_entry, P3_ENTRY_PAGES));
+            }
+            let p2 = p3.next_table(page.p3_index(), &access).unwrap();
+
+            let p2_entry = &p2[page.p2_index()];
+            if !p2_entry.flags().contains(PRESENT) {
+                self.next_index = next_boundary(index, P2_ENTRY_PAGES);
+                continue;
+            }
+            if p2_entry.flags().contains(HUGE_PAGE) {
+                self.next_index = next_boundary(index, P2_ENTRY_PAGES);
+                return Some(leaf_mapping(page, p2_entry, P2_ENTRY_PAGES));
+            }
+            let p1 = p2.next_table(page.p2_index(), &access).unwrap();
+
+            self.next_index = index + 1;
+            let p1_entry = &p1[page.p1_index()];
+            if p1_entry.flags().contains(PRESENT) {
+                return Some(leaf_mapping(page, p1_entry, 1));
+            }
+        }
+        None
+    }
+}
+
+impl<'a, T> Iterator for MappingIter<'a, T>
+    where T: TableAccess
+{
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        while let Some(mapping) = self.next_leaf() {
+            match self.pending.take() {
+                Some(mut pending) => {
+                    if pending.is_continued_by(&mapping) {
+                        pending.size += mapping.size;
+                        self.pending = Some(pending);
+                    } else {
+                        self.pending = Some(mapping);
+                        return Some(pending);
+                    }
+                }
+                None => self.pending = Some(mapping),
+            }
+        }
+        self.pending.take()
+    }
+}
+
+/// Converts a page index into a page number by copying bit 35 into the upper bits, the same way
+/// bit 47 is copied in canonical addresses.
+fn sign_extend(index: usize) -> usize {
+    if index & (PAGE_COUNT >> 1) != 0 {
+        index | (0xffff << 36)
+    } else {
+        index
+    }
+}
+
+/// Returns the first index after `index` that is a multiple of `pages`.
+fn next_boundary(index: usize, pages: usize) -> usize {
+    (index | (pages - 1)) + 1
+}
+
+fn leaf_mapping(page: Page, entry: &Entry, page_count: usize) -> Mapping {
+    Mapping {
+        start: page.start_address(),
+        frame_start: entry.pointed_frame().unwrap().start_address(),
+        size: page_count * PAGE_SIZE,
+        flags: entry.flags() - ACCESSED - DIRTY,
+    }
+}
@@ -0,0 +1,115 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use memory::paging::{self, Page, PageIter, KernelPageTable, Mapper, VirtAddr};
+use memory::{PAGE_SIZE, FrameAllocator};
+
+/// Allocates stacks from a virtual memory region. Each stack has an unmapped guard page below
+/// it, so that a stack overflow causes a page fault instead of silently corrupting memory.
+pub struct StackAllocator {
+    range: PageIter,
+}
+
+impl StackAllocator {
+    pub fn new(page_range: PageIter) -> StackAllocator {
+        StackAllocator { range: page_range }
+    }
+
+    /// Allocates a stack of `size_in_pages` pages and maps it in the active page table.
+    ///
+    /// Returns `None` if the stack region is exhausted or if the stack can't be mapped, e.g.
+    /// because no frames are left. In the latter case, the pages that were already mapped are
+    /// unmapped again.
+    pub fn alloc_stack<A>(&mut self,
+                          active_table: &mut KernelPageTable,
+                          frame_allocator: &mut A,
+                          size_in_pages: usize)
+                          -> Option<Stack>
+        where A: FrameAllocator
+    {
+        if size_in_pages == 0 {
+            return None; // a zero sized stack makes no sense
+        }
+
+        // clone the range, since we only want to change it on success
+        let mut range = self.range.clone();
+
+        // try to allocate the stack pages and a guard page
+        let guard_page = range.next();
+        let stack_start = range.next();
+        let stack_end = if size_in_pages == 1 {
+            stack_start
+        } else {
+            // choose the (size_in_pages-2)th element, since index
+            // starts at 0 and we already allocated the start page
+            range.nth(size_in_pages - 2)
+        };
+
+        match (guard_page, stack_start, stack_end) {
+            (Some(_), Some(start), Some(end)) => {
+                // map stack pages to physical frames, the guard page stays unmapped
+                let flags = paging::WRITABLE | paging::NO_EXECUTE;
+                let mut mapped_end = None;
+                for page in Page::range_inclusive(start, end) {
+                    match active_table.map(page, flags, frame_allocator) {
+                        Ok(flush) => flush.flush(),
+                        Err(_) => {
+                            // unmap the pages that are already mapped and free their frames
+                            if let Some(mapped_end) = mapped_end {
+                                for page in Page::range_inclusive(start, mapped_end) {
+                                    let (frame, flush) = active_table.unmap(page, frame_allocator)
+                                        .expect("stack page was just mapped");
+                                    flush.flush();
+                                    frame_allocator.deallocate_frame(frame);
+                                }
+                            }
+                            return None;
+                        }
+                    }
+                    mapped_end = Some(page);
+                }
+
+                // success! write back updated range
+                self.range = range;
+
+                // create a new stack
+                let top_of_stack = end.start_address() + PAGE_SIZE;
+                Some(Stack::new(top_of_stack, start.start_address()))
+            }
+            _ => None, // not enough pages
+        }
+    }
+}
+
+/// A mapped stack with a guard page below it.
+#[derive(Debug)]
+pub struct Stack {
+    top: VirtAddr,
+    bottom: VirtAddr,
+}
+
+impl Stack {
+    fn new(top: VirtAddr, bottom: VirtAddr) -> Stack {
+        assert!(top > bottom);
+        Stack {
+            top: top,
+            bottom: bottom,
+        }
+    }
+
+    /// Returns the address after the highest stack byte. Stacks grow downwards, so this is the
+    /// initial value of the stack pointer.
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+}
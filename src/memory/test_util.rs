@@ -0,0 +1,67 @@
+// Copyright 2016 Philipp Oppermann. See the README.md
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use memory::{AreaFrameAllocator, BitmapFrameAllocator, FrameAllocator, MemoryArea, PhysAddr,
+             PAGE_SIZE};
+use core::iter::Cloned;
+use core::slice;
+use std::vec::Vec;
+
+/// Returns the memory area from address `start` up to, but not including, `end`.
+pub fn area(start: u64, end: u64) -> MemoryArea {
+    MemoryArea {
+        base_addr: start,
+        length: end - start,
+    }
+}
+
+/// Returns the memory area from frame `start_frame` up to, but not including, `end_frame`.
+pub fn frame_area(start_frame: usize, end_frame: usize) -> MemoryArea {
+    area((start_frame * PAGE_SIZE) as u64, (end_frame * PAGE_SIZE) as u64)
+}
+
+/// Creates an area frame allocator. `kernel` and `multiboot` are inclusive address ranges.
+pub fn area_allocator<'a>(areas: &'a [MemoryArea],
+                          kernel: (usize, usize),
+                          multiboot: (usize, usize))
+                          -> AreaFrameAllocator<Cloned<slice::Iter<'a, MemoryArea>>> {
+    AreaFrameAllocator::new(PhysAddr::new(kernel.0),
+                            PhysAddr::new(kernel.1),
+                            PhysAddr::new(multiboot.0),
+                            PhysAddr::new(multiboot.1),
+                            areas.iter().cloned())
+}
+
+/// Returns a buffer that is large enough for the bitmaps of the given areas.
+pub fn bitmap(areas: &[MemoryArea]) -> Vec<u64> {
+    vec![0; BitmapFrameAllocator::bitmap_words(areas.iter().cloned())]
+}
+
+/// Creates a bitmap frame allocator. `kernel` and `multiboot` are inclusive address ranges.
+pub fn bitmap_allocator<'a>(bitmap: &'a mut [u64],
+                            areas: &[MemoryArea],
+                            kernel: (usize, usize),
+                            multiboot: (usize, usize))
+                            -> BitmapFrameAllocator<'a> {
+    BitmapFrameAllocator::new(bitmap,
+                              PhysAddr::new(kernel.0),
+                              PhysAddr::new(kernel.1),
+                              PhysAddr::new(multiboot.0),
+                              PhysAddr::new(multiboot.1),
+                              areas.iter().cloned())
+}
+
+/// Allocates frames until the allocator is exhausted and returns their numbers.
+pub fn all_frames<A: FrameAllocator>(allocator: &mut A) -> Vec<usize> {
+    let mut frames = Vec::new();
+    while let Some(frame) = allocator.allocate_frame() {
+        frames.push(frame.number);
+    }
+    frames
+}
@@ -18,7 +18,7 @@ const BUFFER_WIDTH: usize = 80;
 pub static WRITER: Mutex<Writer> = Mutex::new(Writer {
     column_position: 0,
     color_code: ColorCode::new(Color::LightGreen, Color::Black),
-    buffer: unsafe { Unique::new(0xb8000 as *mut _) },
+    buffer: unsafe { Unique::new((::memory::KERNEL_OFFSET + 0xb8000) as *mut _) },
 });
 
 macro_rules! println {
@@ -37,6 +37,7 @@ pub fn print(args: fmt::Arguments) {
     WRITER.lock().write_fmt(args).unwrap();
 }
 
+#[cfg(not(test))]
 pub fn clear_screen() {
     for _ in 0..BUFFER_HEIGHT {
         println!("");